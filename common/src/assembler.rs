@@ -0,0 +1,487 @@
+//! Assembles the `.loxasm` text format into a chunk, to write bytecode by hand.
+//!
+//! The format is the disassembler's listing, so a listing assembles back into the chunk it came
+//! from, functions included. One instruction per line, optionally preceded by its offset and its
+//! source line:
+//!
+//! ```text
+//! == script ==                    ; headers are ignored, comments start with ;
+//! .const "greeting"               ; appends a constant to the pool, a number or a "string"
+//! 0000    1 CONSTANT            1 "hello"
+//! 0002    | DEFINE_GLOBAL       0 "greeting"
+//! loop:                           ; labels name the offset of the next instruction
+//!           GET_GLOBAL 0
+//!           JUMP_IF_FALSE -> done
+//!           POP
+//!           LOOP -> loop
+//! done:     NIL
+//!           RETURN
+//! ```
+//!
+//! - Offsets are ignored, a `|` line means the same line as the previous instruction and an
+//!   instruction without a line gets the line of the `.loxasm` file it's on.
+//! - Constant operands are pool indexes. The constant shown after an index, a number or a "string"
+//!   with the escapes of Rust strings, defines it if no `.const` does.
+//! - Jump targets are labels or byte offsets, with or without the listing's `->`.
+//! - A `CLOSURE` is followed by a `local <slot>` or `upvalue <index>` line for each upvalue.
+//! - `.function <name> <arity> <upvalue count>` up to `.end` assembles a function and appends it
+//!   to the enclosing pool, like the listing of a chunk shows the functions in its pool.
+//!   It must come before the `CLOSURE` using it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Enumerate;
+use std::rc::Rc;
+use std::str::Lines;
+
+use crate::chunk::{Chunk, MAX_CONSTANTS};
+use crate::disassembler::opcode_name;
+use crate::function::Function;
+use crate::opcode::Opcode;
+use crate::Constant;
+
+/// Why a `.loxasm` source couldn't be assembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// Line of the `.loxasm` source, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assembles the script's chunk from `.loxasm` source
+pub fn assemble(source: &str) -> Result<Chunk, AssembleError> {
+    let mut assembler = Assembler { lines: source.lines().enumerate() };
+    assembler.chunk(None)
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, AssembleError> {
+    Err(AssembleError { line, message: message.into() })
+}
+
+/// Where a jump goes
+enum Target<'a> {
+    Label(&'a str),
+    Offset(usize),
+}
+
+/// A jump whose distance is written once every label of the chunk is known
+struct Fixup<'a> {
+    source_line: usize,
+    // Offset of the jump opcode
+    offset: usize,
+    opcode: Opcode,
+    target: Target<'a>,
+}
+
+struct Assembler<'a> {
+    lines: Enumerate<Lines<'a>>,
+}
+
+/// State of the chunk being assembled
+struct ChunkState<'a> {
+    chunk: Chunk,
+    labels: HashMap<&'a str, usize>,
+    fixups: Vec<Fixup<'a>>,
+    // Constants quoted after their index in a listing, the pool can refer to them before they appear
+    listed_constants: HashMap<usize, Constant>,
+    // Source line of every constant index used, to check they exist at the end
+    constant_uses: Vec<(usize, usize)>,
+    // Upvalue lines still expected after the last CLOSURE
+    pending_upvalues: usize,
+    line: usize,
+}
+
+impl<'a> Assembler<'a> {
+    /// Assembles lines up to the end of the source, or up to `.end` for a function
+    fn chunk(&mut self, function: Option<(usize, &str)>) -> Result<Chunk, AssembleError> {
+        let mut state = ChunkState {
+            chunk: Chunk::init(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            listed_constants: HashMap::new(),
+            constant_uses: Vec::new(),
+            pending_upvalues: 0,
+            line: 0,
+        };
+
+        loop {
+            let Some((index, text)) = self.lines.next() else {
+                if let Some((line, name)) = function {
+                    return error(line, format!("Function {name} is missing its .end"));
+                }
+                break;
+            };
+            let source_line = index + 1;
+            let text = strip_comment(text).trim();
+            if text.is_empty() || text.starts_with("==") {
+                continue;
+            }
+
+            if let Some(directive) = text.strip_prefix('.') {
+                state.expect_no_pending_upvalues(source_line)?;
+                let (name, rest) = split_token(directive);
+                match name {
+                    "const" => state.chunk.push_constant(parse_constant(source_line, rest)?),
+                    "function" => {
+                        let function = self.function(source_line, rest)?;
+                        state.chunk.push_constant(Constant::Function(Rc::new(function)));
+                    },
+                    "end" if function.is_some() => break,
+                    _ => return error(source_line, format!("Unknown directive .{name}")),
+                }
+                continue;
+            }
+
+            let (first, rest) = split_token(text);
+            let text = match first.strip_suffix(':') {
+                Some(label) => {
+                    state.expect_no_pending_upvalues(source_line)?;
+                    if !is_identifier(label) {
+                        return error(source_line, format!("Invalid label '{label}'"));
+                    }
+                    if state.labels.insert(label, state.chunk.code.len()).is_some() {
+                        return error(source_line, format!("Label '{label}' is defined twice"));
+                    }
+                    rest
+                },
+                None => text,
+            };
+            if !text.is_empty() {
+                state.instruction(source_line, text)?;
+            }
+        }
+
+        state.expect_no_pending_upvalues(state.line)?;
+        state.finish_constants()?;
+        state.patch_jumps()?;
+        Ok(state.chunk)
+    }
+
+    fn function(&mut self, source_line: usize, header: &str) -> Result<Function, AssembleError> {
+        let fields: Vec<&str> = header.split_whitespace().collect();
+        let [name, arity, upvalue_count] = fields[..] else {
+            return error(source_line, "Expected .function <name> <arity> <upvalue count>");
+        };
+        let mut function = Function::init(name);
+        function.arity = parse_number(source_line, arity, "arity")?;
+        function.upvalue_count = parse_number(source_line, upvalue_count, "upvalue count")?;
+        function.chunk = self.chunk(Some((source_line, name)))?;
+        Ok(function)
+    }
+}
+
+impl<'a> ChunkState<'a> {
+    fn instruction(&mut self, source_line: usize, text: &'a str) -> Result<(), AssembleError> {
+        // Up to two numbers before the opcode: the offset, which is ignored, and the line
+        let mut prefix = Vec::new();
+        let mut text = text;
+        loop {
+            let (token, rest) = split_token(text);
+            if prefix.len() == 2 || token.is_empty() || !(token == "|" || token.bytes().all(|byte| byte.is_ascii_digit())) {
+                break;
+            }
+            prefix.push(token);
+            text = rest;
+        }
+        self.line = match prefix.last() {
+            Some(&"|") => self.line,
+            Some(line) => parse_number(source_line, line, "line")?,
+            None => source_line,
+        };
+
+        let (name, operands) = split_token(text);
+        if name == "local" || name == "upvalue" {
+            if self.pending_upvalues == 0 {
+                return error(source_line, format!("'{name}' must follow a CLOSURE"));
+            }
+            self.pending_upvalues -= 1;
+            let index: u8 = parse_number(source_line, operands, "upvalue index")?;
+            self.chunk.write_byte(u8::from(name == "local"), self.line);
+            self.chunk.write_byte(index, self.line);
+            return Ok(());
+        }
+        self.expect_no_pending_upvalues(source_line)?;
+
+        let Some(opcode) = (0..=u8::MAX).filter_map(Opcode::from_byte).find(|opcode| opcode_name(opcode) == name) else {
+            return error(source_line, format!("Unknown opcode '{name}'"));
+        };
+        let offset = self.chunk.code.len();
+        self.chunk.write_opcode(opcode.clone(), self.line);
+
+        match opcode {
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop => {
+                let target = operands.rsplit("->").next().unwrap_or_default().trim_end_matches('\'').trim();
+                let target = match target.parse() {
+                    Ok(offset) => Target::Offset(offset),
+                    Err(_) if is_identifier(target) => Target::Label(target),
+                    Err(_) => return error(source_line, format!("Invalid jump target '{target}'")),
+                };
+                self.fixups.push(Fixup { source_line, offset, opcode, target });
+                // Patched once every label is known
+                self.chunk.write_short(0, self.line);
+            },
+            Opcode::Constant | Opcode::ConstantLong | Opcode::DefineGlobal | Opcode::DefineGlobalLong
+            | Opcode::GetGlobal | Opcode::GetGlobalLong | Opcode::SetGlobal | Opcode::SetGlobalLong
+            | Opcode::Class | Opcode::ClassLong | Opcode::GetProperty | Opcode::GetPropertyLong
+            | Opcode::SetProperty | Opcode::SetPropertyLong | Opcode::Method | Opcode::MethodLong
+            | Opcode::GetSuper | Opcode::GetSuperLong | Opcode::Closure | Opcode::ClosureLong => {
+                let index = self.constant_operand(source_line, operands)?;
+                if opcode.operand_width() == 1 {
+                    let Ok(index) = u8::try_from(index) else {
+                        return error(source_line, format!("Constant {index} doesn't fit in {name}, use its long form"));
+                    };
+                    self.chunk.write_byte(index, self.line);
+                } else {
+                    self.chunk.write_long(index as u32, self.line);
+                }
+                if let (Opcode::Closure | Opcode::ClosureLong, Some(Constant::Function(function))) = (&opcode, self.chunk.constants.get(index)) {
+                    self.pending_upvalues = function.upvalue_count;
+                }
+            },
+            _ if opcode.operand_width() == 1 => {
+                let byte = parse_number(source_line, operands.trim_end_matches('\'').trim(), "operand")?;
+                self.chunk.write_byte(byte, self.line);
+            },
+            _ if !operands.is_empty() => return error(source_line, format!("{name} takes no operand")),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Reads a constant index, remembering the listing's quoted text in case the pool doesn't have it
+    fn constant_operand(&mut self, source_line: usize, operands: &str) -> Result<usize, AssembleError> {
+        let (index, text) = split_token(operands);
+        let index = parse_number(source_line, index, "constant index")?;
+        if index >= MAX_CONSTANTS {
+            return error(source_line, format!("Constant {index} is past the limit of {MAX_CONSTANTS} constants"));
+        }
+        self.constant_uses.push((source_line, index));
+
+        // Functions are shown as <fn name>, they can only be defined by .function
+        if !text.is_empty() && !text.starts_with('<') {
+            let constant = parse_constant(source_line, text)?;
+            self.listed_constants.entry(index).or_insert(constant);
+        }
+        Ok(index)
+    }
+
+    /// Fills the rest of the pool with the constants quoted in the listing, every index used must end up in it
+    fn finish_constants(&mut self) -> Result<(), AssembleError> {
+        while let Some(constant) = self.listed_constants.remove(&self.chunk.constants.len()) {
+            self.chunk.push_constant(constant);
+        }
+        match self.constant_uses.iter().find(|(_, index)| *index >= self.chunk.constants.len()) {
+            Some((source_line, index)) => error(*source_line, format!("Constant {index} is not defined")),
+            None => Ok(()),
+        }
+    }
+
+    fn expect_no_pending_upvalues(&self, source_line: usize) -> Result<(), AssembleError> {
+        match self.pending_upvalues {
+            0 => Ok(()),
+            missing => error(source_line, format!("Expected {missing} more upvalue lines after CLOSURE")),
+        }
+    }
+
+    /// Writes the distance of every jump now that the labels are known
+    fn patch_jumps(&mut self) -> Result<(), AssembleError> {
+        for fixup in &self.fixups {
+            let target = match fixup.target {
+                Target::Offset(offset) => offset,
+                Target::Label(label) => match self.labels.get(label) {
+                    Some(offset) => *offset,
+                    None => return error(fixup.source_line, format!("Undefined label '{label}'")),
+                },
+            };
+            // Jumps are relative to the end of their operand
+            let next = fixup.offset + 3;
+            let distance = match fixup.opcode {
+                Opcode::Loop => next.checked_sub(target),
+                _ => target.checked_sub(next),
+            };
+            let Some(distance) = distance.and_then(|distance| u16::try_from(distance).ok()) else {
+                let direction = if fixup.opcode == Opcode::Loop { "backwards" } else { "forwards" };
+                return error(fixup.source_line, format!("{} can't jump {direction} to {target}", opcode_name(&fixup.opcode)));
+            };
+            let [high, low] = distance.to_be_bytes();
+            self.chunk.code[fixup.offset + 1] = high;
+            self.chunk.code[fixup.offset + 2] = low;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the first whitespace separated token from the rest of the text
+fn split_token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Removes a `;` comment, unless it's inside a string
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => {},
+        }
+    }
+    text
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn parse_number<T: std::str::FromStr>(source_line: usize, text: &str, what: &str) -> Result<T, AssembleError> {
+    match text.parse() {
+        Ok(number) => Ok(number),
+        Err(_) => error(source_line, format!("Invalid {what} '{text}'")),
+    }
+}
+
+/// A number or a double quoted string, as listed by the disassembler
+fn parse_constant(source_line: usize, text: &str) -> Result<Constant, AssembleError> {
+    if let Some(string) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+        return match unescape(string) {
+            Some(string) => Ok(Constant::String(string.into())),
+            None => error(source_line, format!("Invalid escape in {text}")),
+        };
+    }
+    match text.parse() {
+        Ok(number) => Ok(Constant::Number(number)),
+        Err(_) => error(source_line, format!("Invalid constant '{text}', expected a number or a \"string\"")),
+    }
+}
+
+/// Reverses the escapes of Rust's Debug formatting of strings, which the disassembler uses
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let c = match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            c @ ('\\' | '"' | '\'') => c,
+            'u' => {
+                // \u{hex}
+                let rest = chars.as_str().strip_prefix('{')?;
+                let (hex, rest) = rest.split_once('}')?;
+                chars = rest.chars();
+                char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+            },
+            _ => return None,
+        };
+        unescaped.push(c);
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::write_chunk;
+
+    #[test]
+    fn test_round_trip() {
+        let mut chunk = Chunk::init();
+        let name = chunk.add_constant(Constant::String("a".into()));
+        chunk.write_constant(Constant::Number(1.5), 1);
+        chunk.write_indexed(Opcode::DefineGlobal, name, 1);
+        chunk.write_indexed(Opcode::GetGlobal, name, 2);
+        chunk.write_opcode(Opcode::JumpIfFalse, 2);
+        chunk.write_short(3, 2);
+        chunk.write_opcode(Opcode::Pop, 3);
+        chunk.write_opcode(Opcode::Loop, 3);
+        chunk.write_short(10, 3);
+        chunk.write_opcode(Opcode::Push, 4);
+        chunk.write_byte(7, 4);
+        chunk.write_opcode(Opcode::Return, 4);
+
+        let mut listing = String::new();
+        write_chunk(&mut listing, &chunk, "test").unwrap();
+        assert_eq!(assemble(&listing), Ok(chunk));
+    }
+
+    #[test]
+    fn test_round_trip_strings_that_look_like_numbers() {
+        let mut chunk = Chunk::init();
+        chunk.write_constant(Constant::String("42".into()), 1);
+        chunk.write_constant(Constant::Number(42.0), 1);
+        chunk.write_constant(Constant::String("1e3 \"quoted\"\n".into()), 2);
+        chunk.write_opcode(Opcode::Return, 2);
+
+        let mut listing = String::new();
+        write_chunk(&mut listing, &chunk, "test").unwrap();
+        assert_eq!(assemble(&listing), Ok(chunk));
+    }
+
+    #[test]
+    fn test_labels_and_constants() {
+        let chunk = assemble("
+            .const \"it's; here\"  ; a comment
+            .const 2
+            start:
+                CONSTANT 1
+                JUMP_IF_FALSE -> end
+                POP
+                LOOP start
+            end: RETURN
+        ").unwrap();
+        assert_eq!(chunk.constants, vec![Constant::String("it's; here".into()), Constant::Number(2.0)]);
+        assert_eq!(chunk.code, vec![0, 1, 22, 0, 4, 15, 24, 0, 9, 1]);
+        assert_eq!(chunk.get_line(0), 5);
+    }
+
+    #[test]
+    fn test_function() {
+        let chunk = assemble("
+            .function inner 1 1
+                GET_UPVALUE 0
+                RETURN
+            .end
+            NIL
+            CLOSURE 0
+                local 0
+        ").unwrap();
+        let Constant::Function(function) = &chunk.constants[0] else { panic!("Expected a function") };
+        assert_eq!(function.name, "inner");
+        assert_eq!(function.arity, 1);
+        assert_eq!(function.chunk.code, vec![27, 0, 1]);
+        assert_eq!(chunk.code, vec![7, 26, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(error("NIL\nFROB"), "line 2: Unknown opcode 'FROB'");
+        assert_eq!(error("JUMP nowhere"), "line 1: Undefined label 'nowhere'");
+        assert_eq!(error("CONSTANT 3"), "line 1: Constant 3 is not defined");
+        assert_eq!(error("CONSTANT 0 1\nLOOP 10"), "line 2: LOOP can't jump backwards to 10");
+        assert_eq!(error("NIL 1"), "line 1: NIL takes no operand");
+        assert_eq!(error(".function f 0 0\nNIL"), "line 1: Function f is missing its .end");
+        assert_eq!(error(".end"), "line 1: Unknown directive .end");
+    }
+}
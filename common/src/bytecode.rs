@@ -0,0 +1,326 @@
+//! The `.loxc` file format, to compile a script once and run it later.
+//!
+//! Every number is little-endian. A file is the magic bytes and the format version,
+//! followed by the script's chunk:
+//!
+//! ```text
+//! chunk    := code_len: u32, code: [u8], span_count: u32, span*, constant_count: u32, constant*
+//! span     := offset: u32, line: u32, column: u32, start: u32, end: u32
+//! constant := 0: u8, number: f64
+//!           | 1: u8, string
+//!           | 2: u8, arity: u8, upvalue_count: u32, name: string, chunk
+//! string   := len: u32, utf-8 bytes
+//! ```
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::function::Function;
+use crate::interner::Interner;
+use crate::span::Span;
+use crate::Constant;
+
+pub const MAGIC: [u8; 4] = *b"LOXC";
+/// Bumped on every incompatible change to the format
+pub const FORMAT_VERSION: u16 = 3;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+// Functions nest at most this deep, so a crafted file can't overflow the stack while loading
+const MAX_NESTING: usize = 256;
+
+/// Why a `.loxc` file couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// The file doesn't start with the `.loxc` magic bytes
+    NotBytecode,
+    /// The file was written by a newer version of the format
+    UnsupportedVersion(u16),
+    /// The file ends in the middle of the chunk
+    Truncated,
+    /// There is data after the end of the chunk
+    TrailingData,
+    UnknownConstantTag(u8),
+    InvalidString,
+    TooDeeplyNested,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "could not read bytecode: {err}"),
+            LoadError::NotBytecode => write!(f, "not a .loxc file, the magic header is missing"),
+            LoadError::UnsupportedVersion(version) =>
+                write!(f, "unsupported bytecode version {version}, expected {FORMAT_VERSION}"),
+            LoadError::Truncated => write!(f, "bytecode file is truncated"),
+            LoadError::TrailingData => write!(f, "unexpected data after the end of the bytecode"),
+            LoadError::UnknownConstantTag(tag) => write!(f, "unknown constant tag {tag}"),
+            LoadError::InvalidString => write!(f, "string constant is not valid UTF-8"),
+            LoadError::TooDeeplyNested => write!(f, "functions are nested more than {MAX_NESTING} levels deep"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => LoadError::Truncated,
+            _ => LoadError::Io(err),
+        }
+    }
+}
+
+impl Chunk {
+    /// Serializes the chunk, with the header, in the `.loxc` format
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        write_chunk(&mut writer, self)
+    }
+
+    /// Loads a chunk written by `write_to`, the whole reader must be a single chunk
+    pub fn read_from(mut reader: impl Read) -> Result<Chunk, LoadError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).map_err(|err| match LoadError::from(err) {
+            LoadError::Truncated => LoadError::NotBytecode,
+            err => err,
+        })?;
+        if magic != MAGIC {
+            return Err(LoadError::NotBytecode);
+        }
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+
+        let mut loader = Loader { reader, interner: Interner::init() };
+        let chunk = loader.read_chunk(0)?;
+        if loader.reader.read(&mut [0])? != 0 {
+            return Err(LoadError::TrailingData);
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &Chunk) -> io::Result<()> {
+    write_len(writer, chunk.code.len())?;
+    writer.write_all(&chunk.code)?;
+    write_len(writer, chunk.spans.len())?;
+    for (offset, span) in &chunk.spans {
+        for field in [*offset, span.line, span.column, span.start, span.end] {
+            write_len(writer, field)?;
+        }
+    }
+    write_len(writer, chunk.constants.len())?;
+    for constant in &chunk.constants {
+        match constant {
+            Constant::Number(number) => {
+                writer.write_all(&[TAG_NUMBER])?;
+                writer.write_all(&number.to_le_bytes())?;
+            },
+            Constant::String(s) => {
+                writer.write_all(&[TAG_STRING])?;
+                write_string(writer, s)?;
+            },
+            Constant::Function(function) => {
+                writer.write_all(&[TAG_FUNCTION, function.arity])?;
+                write_len(writer, function.upvalue_count)?;
+                write_string(writer, &function.name)?;
+                write_chunk(writer, &function.chunk)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+fn write_len(writer: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too long for a .loxc file"))?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+fn write_string(writer: &mut impl Write, s: &str) -> io::Result<()> {
+    write_len(writer, s.len())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], LoadError> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+struct Loader<R: Read> {
+    reader: R,
+    // Names repeat across functions, share them like the compiler does
+    interner: Interner,
+}
+
+impl<R: Read> Loader<R> {
+    fn read_chunk(&mut self, depth: usize) -> Result<Chunk, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(LoadError::TooDeeplyNested);
+        }
+        let mut chunk = Chunk::init();
+        let code_len = self.read_len()?;
+        chunk.code = self.read_bytes(code_len)?;
+        let span_count = self.read_len()?;
+        for _ in 0..span_count {
+            let offset = self.read_len()?;
+            let [line, column, start, end] = [self.read_len()?, self.read_len()?, self.read_len()?, self.read_len()?];
+            chunk.spans.push((offset, Span { line, column, start, end }));
+        }
+        let constant_count = self.read_len()?;
+        for _ in 0..constant_count {
+            let constant = self.read_constant(depth)?;
+            chunk.push_constant(constant);
+        }
+        Ok(chunk)
+    }
+
+    fn read_constant(&mut self, depth: usize) -> Result<Constant, LoadError> {
+        let [tag] = read_array(&mut self.reader)?;
+        match tag {
+            TAG_NUMBER => Ok(Constant::Number(f64::from_le_bytes(read_array(&mut self.reader)?))),
+            TAG_STRING => Ok(Constant::String(self.read_string()?)),
+            TAG_FUNCTION => {
+                let [arity] = read_array(&mut self.reader)?;
+                let upvalue_count = self.read_len()?;
+                let name = self.read_string()?;
+                let mut function = Function::init(&name);
+                function.arity = arity;
+                function.upvalue_count = upvalue_count;
+                function.chunk = self.read_chunk(depth + 1)?;
+                Ok(Constant::Function(Rc::new(function)))
+            },
+            _ => Err(LoadError::UnknownConstantTag(tag)),
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(read_array(&mut self.reader)?))
+    }
+
+    fn read_len(&mut self) -> Result<usize, LoadError> {
+        Ok(self.read_u32()? as usize)
+    }
+
+    // Reads incrementally instead of trusting the length to allocate, the file may be truncated
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, LoadError> {
+        let mut bytes = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(LoadError::Truncated);
+        }
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> Result<Rc<str>, LoadError> {
+        let len = self.read_len()?;
+        let bytes = self.read_bytes(len)?;
+        let s = std::str::from_utf8(&bytes).map_err(|_| LoadError::InvalidString)?;
+        Ok(self.interner.intern(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Opcode;
+
+    fn sample_chunk() -> Chunk {
+        let mut function = Function::init("add");
+        function.arity = 2;
+        function.upvalue_count = 1;
+        function.chunk.write_opcode(Opcode::GetLocal, 2);
+        function.chunk.write_byte(1, 2);
+        function.chunk.write_opcode(Opcode::Return, 2);
+
+        let mut chunk = Chunk::init();
+        chunk.set_span(Span { line: 1, column: 3, start: 2, end: 5 });
+        chunk.write_constant(Constant::Number(1.5), 1);
+        chunk.write_constant(Constant::String("héllo".into()), 1);
+        chunk.write_constant(Constant::Function(Rc::new(function)), 3);
+        chunk.write_opcode(Opcode::Return, 4);
+        chunk
+    }
+
+    fn to_bytes(chunk: &Chunk) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        chunk.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let chunk = sample_chunk();
+        let loaded = Chunk::read_from(to_bytes(&chunk).as_slice()).unwrap();
+        assert_eq!(loaded, chunk);
+        assert_eq!(loaded.spans, chunk.spans);
+    }
+
+    #[test]
+    fn test_header() {
+        let bytes = to_bytes(&Chunk::init());
+        assert_eq!(bytes[..4], MAGIC);
+        assert_eq!(bytes[4..6], FORMAT_VERSION.to_le_bytes());
+    }
+
+    #[test]
+    fn test_loaded_chunk_keeps_duplicate_constants() {
+        let mut chunk = Chunk::init();
+        chunk.push_constant(Constant::Number(1.0));
+        chunk.push_constant(Constant::Number(1.0));
+        let loaded = Chunk::read_from(to_bytes(&chunk).as_slice()).unwrap();
+        assert_eq!(loaded.constants.len(), 2);
+    }
+
+    #[test]
+    fn test_reject_missing_magic() {
+        let result = Chunk::read_from("print 1;".as_bytes());
+        assert!(matches!(result, Err(LoadError::NotBytecode)));
+        assert!(matches!(Chunk::read_from(&[][..]), Err(LoadError::NotBytecode)));
+    }
+
+    #[test]
+    fn test_reject_future_version() {
+        let mut bytes = to_bytes(&sample_chunk());
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let error = Chunk::read_from(bytes.as_slice()).unwrap_err();
+        assert!(matches!(error, LoadError::UnsupportedVersion(4)));
+        assert_eq!(error.to_string(), "unsupported bytecode version 4, expected 3");
+    }
+
+    #[test]
+    fn test_reject_truncated() {
+        let bytes = to_bytes(&sample_chunk());
+        // Cutting the file anywhere after the header must fail cleanly
+        for len in 6..bytes.len() {
+            let result = Chunk::read_from(&bytes[..len]);
+            assert!(matches!(result, Err(LoadError::Truncated)), "length {len}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn test_reject_trailing_data() {
+        let mut bytes = to_bytes(&sample_chunk());
+        bytes.push(0);
+        assert!(matches!(Chunk::read_from(bytes.as_slice()), Err(LoadError::TrailingData)));
+    }
+
+    #[test]
+    fn test_reject_unknown_constant_tag() {
+        let mut chunk = Chunk::init();
+        chunk.add_constant(Constant::Number(1.0));
+        let mut bytes = to_bytes(&chunk);
+        // Header, empty code, no spans and the constant count, then the tag
+        bytes[6 + 4 + 4 + 4] = 9;
+        assert!(matches!(Chunk::read_from(bytes.as_slice()), Err(LoadError::UnknownConstantTag(9))));
+    }
+}
@@ -0,0 +1,185 @@
+//! Control-flow graph of a chunk, split in basic blocks, exported to Graphviz DOT.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::disassembler::{disassemble, write_instruction, Instruction};
+use crate::opcode::Opcode;
+use crate::Constant;
+
+/// Why control goes from a block to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Falls through to the next instruction
+    Next,
+    /// Jump or Loop
+    Jump,
+    /// JumpIfFalse when the condition is truthy, it doesn't jump
+    True,
+    /// JumpIfFalse when the condition is falsey
+    False,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    /// Offset of the block the edge goes to, the length of the code for the end of the chunk
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that always run one after the other, only the first one is a jump target
+/// and only the last one jumps
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock<'a> {
+    pub instructions: Vec<Instruction<'a>>,
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock<'_> {
+    /// Offset of the first instruction
+    pub fn start(&self) -> usize {
+        self.instructions[0].offset
+    }
+}
+
+/// Splits the chunk in basic blocks, at jump targets and after every jump or return
+pub fn basic_blocks(chunk: &Chunk) -> Vec<BasicBlock<'_>> {
+    let instructions = disassemble(chunk);
+
+    // Offsets where a block starts
+    let mut leaders = BTreeSet::from([0]);
+    for instruction in &instructions {
+        if let Some(target) = instruction.jump_target {
+            leaders.insert(target);
+        }
+        if matches!(instruction.opcode, Some(Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop | Opcode::Return)) {
+            leaders.insert(instruction.offset + instruction.len);
+        }
+    }
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    for instruction in instructions {
+        match blocks.last_mut() {
+            Some(block) if !leaders.contains(&instruction.offset) => block.instructions.push(instruction),
+            _ => blocks.push(BasicBlock { instructions: vec![instruction], successors: Vec::new() }),
+        }
+    }
+
+    for block in &mut blocks {
+        let last = block.instructions.last().expect("Blocks are never empty");
+        let next = last.offset + last.len;
+        // A jump target that can't be decoded is left out, the graph would have nowhere to point it
+        let target = last.jump_target;
+        block.successors = match (&last.opcode, target) {
+            (Some(Opcode::Return), _) => vec![],
+            (Some(Opcode::Jump | Opcode::Loop), Some(target)) => vec![Edge { target, kind: EdgeKind::Jump }],
+            (Some(Opcode::JumpIfFalse), Some(target)) => vec![
+                Edge { target: next, kind: EdgeKind::True },
+                Edge { target, kind: EdgeKind::False },
+            ],
+            (Some(Opcode::Jump | Opcode::Loop | Opcode::JumpIfFalse), None) => vec![],
+            _ => vec![Edge { target: next, kind: EdgeKind::Next }],
+        };
+    }
+    blocks
+}
+
+/// Writes the graph of the chunk and of every function in it, each function in its own cluster
+pub fn write_dot(out: &mut impl fmt::Write, chunk: &Chunk, name: &str) -> fmt::Result {
+    writeln!(out, "digraph \"{}\" {{", escape(name))?;
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+    let mut next_id = 0;
+    write_cluster(out, chunk, name, &mut next_id)?;
+    writeln!(out, "}}")
+}
+
+fn write_cluster(out: &mut impl fmt::Write, chunk: &Chunk, name: &str, next_id: &mut usize) -> fmt::Result {
+    // Node names are prefixed by the cluster id, offsets repeat between functions
+    let id = *next_id;
+    *next_id += 1;
+    writeln!(out, "    subgraph cluster_{id} {{")?;
+    writeln!(out, "        label=\"{}\";", escape(name))?;
+
+    let blocks = basic_blocks(chunk);
+    for block in &blocks {
+        let mut listing = String::new();
+        for instruction in &block.instructions {
+            write_instruction(&mut listing, chunk, instruction)?;
+        }
+        // \l ends a left-aligned line in DOT labels
+        let label = escape(&listing).replace('\n', "\\l");
+        writeln!(out, "        f{id}_{} [label=\"{label}\"];", block.start())?;
+    }
+    writeln!(out, "        f{id}_{} [label=\"end\", shape=oval];", chunk.code.len())?;
+    for block in &blocks {
+        for edge in &block.successors {
+            write!(out, "        f{id}_{} -> f{id}_{}", block.start(), edge.target)?;
+            match edge.kind {
+                EdgeKind::True => writeln!(out, " [label=\"true\"];")?,
+                EdgeKind::False => writeln!(out, " [label=\"false\"];")?,
+                EdgeKind::Next | EdgeKind::Jump => writeln!(out, ";")?,
+            }
+        }
+    }
+    writeln!(out, "    }}")?;
+
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            write_cluster(out, &function.chunk, &function.name, next_id)?;
+        }
+    }
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // if (nil) print 1; else print 2;
+    fn if_else() -> Chunk {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_opcode(Opcode::JumpIfFalse, 1);
+        chunk.write_short(7, 1);
+        chunk.write_opcode(Opcode::Pop, 1);
+        chunk.write_constant(Constant::Number(1.0), 1);
+        chunk.write_opcode(Opcode::Print, 1);
+        chunk.write_opcode(Opcode::Jump, 1);
+        chunk.write_short(4, 1);
+        chunk.write_opcode(Opcode::Pop, 1);
+        chunk.write_constant(Constant::Number(2.0), 1);
+        chunk.write_opcode(Opcode::Print, 1);
+        chunk
+    }
+
+    #[test]
+    fn test_basic_blocks() {
+        let chunk = if_else();
+        let blocks = basic_blocks(&chunk);
+        let starts: Vec<usize> = blocks.iter().map(BasicBlock::start).collect();
+        assert_eq!(starts, vec![0, 4, 11]);
+        assert_eq!(blocks[0].successors, vec![
+            Edge { target: 4, kind: EdgeKind::True },
+            Edge { target: 11, kind: EdgeKind::False },
+        ]);
+        assert_eq!(blocks[1].successors, vec![Edge { target: 15, kind: EdgeKind::Jump }]);
+        assert_eq!(blocks[2].successors, vec![Edge { target: 15, kind: EdgeKind::Next }]);
+    }
+
+    #[test]
+    fn test_write_dot() {
+        let mut dot = String::new();
+        write_dot(&mut dot, &if_else(), "script").unwrap();
+        assert!(dot.starts_with("digraph \"script\" {\n"));
+        assert!(dot.contains("f0_0 [label=\"0000    1 NIL\\l0001    | JUMP_IF_FALSE       1 ->   11'\\l\"];"));
+        assert!(dot.contains("f0_0 -> f0_4 [label=\"true\"];"));
+        assert!(dot.contains("f0_0 -> f0_11 [label=\"false\"];"));
+        assert!(dot.contains("f0_4 -> f0_15;"));
+        assert!(dot.contains("f0_15 [label=\"end\", shape=oval];"));
+    }
+}
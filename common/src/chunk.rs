@@ -1,25 +1,90 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use crate::Constant;
 use crate::opcode::Opcode;
+use crate::span::Span;
+
+/// Constants addressable by the long form of an instruction
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
-    pub constants: Vec<Constant>
+    pub constants: Vec<Constant>,
+    // Index of every number and string added, so that adding them again reuses the entry
+    constant_indexes: HashMap<ConstantKey, usize>,
+    // Source span of the code from each offset on, an entry is only added when the span changes.
+    // It's also where the lines are kept, code written without a span gets one with only its line.
+    pub(crate) spans: Vec<(usize, Span)>,
+}
+
+/// What makes two constants the same entry in the pool
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    // By bit pattern, so that NaN is equal to itself and -0.0 is different from 0.0
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl ConstantKey {
+    // Functions are never shared, each declaration is its own constant
+    fn of(constant: &Constant) -> Option<ConstantKey> {
+        match constant {
+            Constant::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            Constant::String(s) => Some(ConstantKey::String(s.clone())),
+            Constant::Function(_) => None,
+        }
+    }
+}
+
+// Spans are left out, chunks from the compiler and from the assembler can be the same code
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code
+            && self.constants == other.constants
+            && (0..self.code.len()).all(|index| self.get_line(index) == other.get_line(index))
+    }
 }
 
 impl Chunk {
     pub fn init() -> Chunk {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
             constants: Vec::new(),
+            constant_indexes: HashMap::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// Marks the code written from now on as coming from the given span of the source
+    pub fn set_span(&mut self, span: Span) {
+        let offset = self.code.len();
+        match self.spans.last_mut() {
+            Some((_, last)) if *last == span => {},
+            // Nothing was written with the previous span
+            Some((last_offset, last)) if *last_offset == offset => *last = span,
+            _ => self.spans.push((offset, span)),
+        }
+    }
+
+    /// Source span of the byte at the given index, None if it's past the end
+    pub fn get_span(&self, index: usize) -> Option<Span> {
+        if index >= self.code.len() {
+            return None;
         }
+        let entry = self.spans.partition_point(|(offset, _)| *offset <= index);
+        entry.checked_sub(1).map(|entry| self.spans[entry].1)
     }
 
     /// Write a raw byte, should be seldom used and rather use the other functions
     pub fn write_byte(&mut self, byte: u8, line: usize) {
+        match self.spans.last() {
+            Some((_, span)) if span.line == line => {},
+            // The span set for this code doesn't agree on the line, the line wins
+            _ => self.set_span(Span { line, ..Span::default() }),
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
     /// Write a raw short (2 bytes)
@@ -28,52 +93,173 @@ impl Chunk {
         self.write_byte(short as u8, line);
     }
 
+    /// Write a raw 24 bits operand (3 bytes)
+    pub fn write_long(&mut self, long: u32, line: usize) {
+        self.write_byte((long >> 16) as u8, line);
+        self.write_short(long as u16, line);
+    }
+
     /// Write an opcode to the chunk
     pub fn write_opcode(&mut self, opcode: Opcode, line: usize) {
         self.write_byte(opcode as u8, line);
     }
 
-    /// Write a constant to the constant array and return it's index
+    /// Write a constant to the constant array and return it's index.
+    /// Numbers and strings already in the array are not added again, their index is returned instead.
     pub fn add_constant(&mut self, constant: Constant) -> usize {
+        let key = ConstantKey::of(&constant);
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indexes.get(key)) {
+            return *index;
+        }
         self.constants.push(constant);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indexes.insert(key, index);
+        }
+        index
     }
 
-    pub fn read_constant(&self, index: usize) -> &Constant {
-        &self.constants[index]
+    /// Appends a constant at the next index even if an equal one exists, so loaded chunks keep their indexes
+    pub(crate) fn push_constant(&mut self, constant: Constant) {
+        if let Some(key) = ConstantKey::of(&constant) {
+            self.constant_indexes.entry(key).or_insert(self.constants.len());
+        }
+        self.constants.push(constant);
+    }
+
+    pub fn read_constant(&self, index: usize) -> Option<&Constant> {
+        self.constants.get(index)
     }
 
     /// Add a constant, write a CONSTANT opcode followed by the index
     pub fn write_constant(&mut self, constant: Constant, line: usize) -> usize {
         let constant_index = self.add_constant(constant);
-        self.write_opcode(Opcode::Constant, line);
-        self.write_byte(constant_index as u8, line);
+        self.write_indexed(Opcode::Constant, constant_index, line);
         constant_index
     }
 
+    /// Write an instruction followed by its index operand (a constant, a local slot...).
+    /// Instructions with a long form switch to it when the index doesn't fit in a byte.
+    /// Panics if the index doesn't fit in the operand, callers check their limits before writing.
+    pub fn write_indexed(&mut self, opcode: Opcode, index: usize, line: usize) {
+        match opcode.long_form() {
+            Some(long_opcode) if index > u8::MAX as usize => {
+                assert!(index < MAX_CONSTANTS, "Index {index} doesn't fit in the operand of {long_opcode}");
+                self.write_opcode(long_opcode, line);
+                self.write_long(index as u32, line);
+            },
+            _ => {
+                assert!(index <= u8::MAX as usize, "Index {index} doesn't fit in the operand of {opcode}");
+                self.write_opcode(opcode, line);
+                self.write_byte(index as u8, line);
+            },
+        }
+    }
+
     /// Write a variable's name as constant to the chunk's constant table.
     // Globals are looked up by name during runtime and the name is too big
     // to fit in the stack so we ought to save it here.
-    pub fn write_identifier_constant(&mut self, ident: String) -> usize {
+    pub fn write_identifier_constant(&mut self, ident: Rc<str>) -> usize {
         self.add_constant(Constant::String(ident))
     }
 
-    /// Reads a byte from the code chunk given an index
-    pub fn read_byte(&self, index: usize) -> u8 {
-        self.code[index]
+    /// Reads a byte from the code chunk given an index, None if it's past the end
+    pub fn read_byte(&self, index: usize) -> Option<u8> {
+        self.code.get(index).copied()
     }
 
     /// Reads a byte from the code chunk given and index and ensures it's an opcode
-    pub fn read_opcode(&self, index: usize) -> Opcode {
-        let opcode = self.read_byte(index);
-        Opcode::from_byte(opcode)
+    pub fn read_opcode(&self, index: usize) -> Option<Opcode> {
+        self.read_byte(index).and_then(Opcode::from_byte)
     }
 
+    /// Source line of the byte at the given index, 0 if it's past the end
     pub fn get_line(&self, index: usize) -> usize {
-        self.lines[index]
+        self.get_span(index).map_or(0, |span| span.line)
     }
 
     pub fn code_len(&self) -> usize {
         self.code.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::Function;
+
+    #[test]
+    fn test_add_constant_reuses_numbers() {
+        let mut chunk = Chunk::init();
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Constant::Number(2.0)), 1);
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn test_add_constant_compares_numbers_by_bits() {
+        let mut chunk = Chunk::init();
+        assert_eq!(chunk.add_constant(Constant::Number(0.0)), 0);
+        assert_eq!(chunk.add_constant(Constant::Number(-0.0)), 1);
+        assert_eq!(chunk.add_constant(Constant::Number(f64::NAN)), 2);
+        assert_eq!(chunk.add_constant(Constant::Number(f64::NAN)), 2);
+    }
+
+    #[test]
+    fn test_add_constant_reuses_strings() {
+        let mut chunk = Chunk::init();
+        assert_eq!(chunk.add_constant(Constant::String("a".into())), 0);
+        assert_eq!(chunk.add_constant(Constant::String("b".into())), 1);
+        // Equal content is enough, it doesn't need to be the same allocation
+        assert_eq!(chunk.add_constant(Constant::String("a".into())), 0);
+    }
+
+    #[test]
+    fn test_add_constant_never_reuses_functions() {
+        let mut chunk = Chunk::init();
+        let function = Rc::new(Function::init("f"));
+        assert_eq!(chunk.add_constant(Constant::Function(function.clone())), 0);
+        assert_eq!(chunk.add_constant(Constant::Function(function)), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 256 doesn't fit in the operand of GetLocal")]
+    fn test_write_indexed_without_long_form_never_wraps() {
+        let mut chunk = Chunk::init();
+        chunk.write_indexed(Opcode::GetLocal, 256, 1);
+    }
+
+    #[test]
+    fn test_spans_are_stored_when_they_change() {
+        let first = Span { line: 1, column: 1, start: 0, end: 1 };
+        let second = Span { line: 1, column: 5, start: 4, end: 9 };
+        let mut chunk = Chunk::init();
+        chunk.set_span(first);
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.set_span(first);
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.set_span(first);
+        chunk.set_span(second);
+        chunk.write_constant(Constant::Number(1.0), 1);
+
+        assert_eq!(chunk.spans, vec![(0, first), (2, second)]);
+        assert_eq!(chunk.get_span(1), Some(first));
+        assert_eq!(chunk.get_span(3), Some(second));
+        assert_eq!(chunk.get_span(4), None);
+    }
+
+    #[test]
+    fn test_lines_are_kept_in_the_spans() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_constant(Constant::Number(1.0), 3);
+        chunk.write_opcode(Opcode::Return, 3);
+
+        assert_eq!(chunk.spans.len(), 2);
+        let lines: Vec<usize> = (0..chunk.code_len()).map(|index| chunk.get_line(index)).collect();
+        assert_eq!(lines, vec![1, 1, 3, 3, 3]);
+        assert_eq!(chunk.get_line(5), 0);
+    }
+}
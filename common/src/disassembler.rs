@@ -1,106 +1,350 @@
+use std::fmt;
+
 use crate::chunk::Chunk;
+use crate::Constant;
 use crate::opcode::Opcode;
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {name} ==");
-    let mut offset: usize = 0;
-    while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
+/// An operand of an instruction, decoded from the bytes following its opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// Index in the constant pool
+    Constant(usize),
+    /// Stack slot, argument count or upvalue index
+    Byte(u8),
+    /// Distance of a jump, backwards for Loop
+    Jump(u16),
+    /// A variable captured by a closure, from the enclosing function's locals or upvalues
+    Upvalue { is_local: bool, index: u8 },
+}
+
+/// A decoded instruction of a chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction<'a> {
+    pub offset: usize,
+    pub line: usize,
+    /// None if the byte at the offset isn't a known opcode
+    pub opcode: Option<Opcode>,
+    /// Empty if the code ends before the operands
+    pub operands: Vec<Operand>,
+    /// The constant pointed to by the operand, for instructions taking a constant index
+    pub constant: Option<&'a Constant>,
+    /// Offset the instruction jumps to, for Jump, JumpIfFalse and Loop
+    pub jump_target: Option<usize>,
+    /// Number of bytes taken by the opcode and its operands
+    pub len: usize,
+}
+
+/// Decodes every instruction of the chunk
+pub fn disassemble(chunk: &Chunk) -> Vec<Instruction<'_>> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while let Some(instruction) = decode_instruction(chunk, offset) {
+        offset += instruction.len;
+        instructions.push(instruction);
     }
+    instructions
 }
 
-/// Disassemble a single instruction
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+/// Decodes the instruction starting at the given offset, None if the offset is past the end of the code.
+/// Operands missing from truncated code are left out rather than read past the end.
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Option<Instruction<'_>> {
+    let byte = chunk.read_byte(offset)?;
+    let mut instruction = Instruction {
+        offset,
+        line: chunk.get_line(offset),
+        opcode: Opcode::from_byte(byte),
+        operands: Vec::new(),
+        constant: None,
+        jump_target: None,
+        len: 1,
+    };
+    let Some(opcode) = &instruction.opcode else {
+        return Some(instruction);
+    };
 
-    // If this instruction is in the same line as the previous don't show a new line show a |
-    // Else, if it has changed, show the line number.
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
-    } else {
-        print!("{:4} ", chunk.lines[offset]);
+    let width = opcode.operand_width();
+    let Some(bytes) = chunk.code.get(offset + 1..offset + 1 + width) else {
+        // Truncated code, the rest of the bytes can't be an instruction
+        instruction.len = chunk.code.len() - offset;
+        return Some(instruction);
+    };
+    let operand = bytes.iter().fold(0, |operand, byte| operand << 8 | *byte as usize);
+    instruction.len += width;
+
+    match opcode {
+        Opcode::Constant | Opcode::ConstantLong | Opcode::DefineGlobal | Opcode::DefineGlobalLong
+        | Opcode::GetGlobal | Opcode::GetGlobalLong | Opcode::SetGlobal | Opcode::SetGlobalLong
+        | Opcode::Class | Opcode::ClassLong | Opcode::GetProperty | Opcode::GetPropertyLong
+        | Opcode::SetProperty | Opcode::SetPropertyLong | Opcode::Method | Opcode::MethodLong
+        | Opcode::GetSuper | Opcode::GetSuperLong | Opcode::Closure | Opcode::ClosureLong => {
+            instruction.operands.push(Operand::Constant(operand));
+            instruction.constant = chunk.constants.get(operand);
+        },
+        Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop => {
+            instruction.operands.push(Operand::Jump(operand as u16));
+            let next = offset + instruction.len;
+            instruction.jump_target = match opcode {
+                Opcode::Loop => next.checked_sub(operand),
+                _ => Some(next + operand),
+            };
+        },
+        _ if width == 1 => instruction.operands.push(Operand::Byte(operand as u8)),
+        _ => {},
     }
 
-    let instruction = chunk.code[offset];
-    let maybe_opcode = num::FromPrimitive::from_u8(instruction);
-    let to_ret = match maybe_opcode {
-        Some(op) => {
-            let disasm = |name| {
-                disassemble_simple(name, offset)
+    // Each upvalue captured by a closure takes a pair of bytes after the constant
+    let is_closure = matches!(opcode, Opcode::Closure | Opcode::ClosureLong);
+    if let Some(Constant::Function(function)) = instruction.constant.filter(|_| is_closure) {
+        for _ in 0..function.upvalue_count {
+            let Some(pair) = chunk.code.get(offset + instruction.len..offset + instruction.len + 2) else {
+                instruction.len = chunk.code.len() - offset;
+                break;
             };
-            match op {
-                Opcode::Return => disasm("RETURN"),
-                Opcode::Not => disasm("NOT"),
-                Opcode::Equal => disasm("EQUAL"),
-                Opcode::Greater => disasm("GREATER"),
-                Opcode::Less => disasm("LESS"),
-                Opcode::Negate => disasm("NEGATE"),
-                Opcode::Add => disasm("ADD"),
-                Opcode::Subtract => disasm("SUBTRACT"),
-                Opcode::Multiply => disasm("MULTIPLY"),
-                Opcode::Divide => disasm("DIVIDE"),
-                Opcode::Constant => disassemble_constant("CONSTANT", chunk, offset),
-                Opcode::Nil => disasm("NIL"),
-                Opcode::False => disasm("FALSE"),
-                Opcode::True => disasm("TRUE"),
-                Opcode::Print => disasm("PRINT"),
-                Opcode::Pop => disasm("POP"),
-                Opcode::DefineGlobal => disassemble_constant("DEFINE_GLOBAL", chunk, offset),
-                Opcode::GetGlobal => disassemble_constant("GET_GLOBAL", chunk, offset),
-                Opcode::SetGlobal => disassemble_constant("SET_GLOBAL", chunk, offset),
-                Opcode::GetLocal => disassemble_get_local("GET_LOCAL", chunk, offset),
-                Opcode::SetLocal => disassemble_get_local("SET_LOCAL", chunk, offset),
-                Opcode::Jump => disassemble_short_jump("JUMP", 1, chunk, offset),
-                Opcode::JumpIfFalse => disassemble_short_jump("JUMP_IF_FALSE", 1, chunk, offset),
-                Opcode::Push => disassemble_get_local("PUSH", chunk, offset),
-            }
-        }
-        None => {
-            println!("Unknown opcode {instruction}");
-            offset + 1
+            instruction.operands.push(Operand::Upvalue { is_local: pair[0] == 1, index: pair[1] });
+            instruction.len += 2;
         }
-    };
-    to_ret
+    }
+    Some(instruction)
 }
 
-// Disassemble a simple (1 byte) opcode
-fn disassemble_simple(name: &str, offset: usize) -> usize {
-    println!("{name}");
-    offset + 1
+/// Name of the opcode as shown in listings
+pub(crate) fn opcode_name(opcode: &Opcode) -> &'static str {
+    match opcode {
+        Opcode::Constant => "CONSTANT",
+        Opcode::Return => "RETURN",
+        Opcode::Negate => "NEGATE",
+        Opcode::Add => "ADD",
+        Opcode::Subtract => "SUBTRACT",
+        Opcode::Multiply => "MULTIPLY",
+        Opcode::Divide => "DIVIDE",
+        Opcode::Nil => "NIL",
+        Opcode::True => "TRUE",
+        Opcode::False => "FALSE",
+        Opcode::Not => "NOT",
+        Opcode::Equal => "EQUAL",
+        Opcode::Greater => "GREATER",
+        Opcode::Less => "LESS",
+        Opcode::Print => "PRINT",
+        Opcode::Pop => "POP",
+        Opcode::DefineGlobal => "DEFINE_GLOBAL",
+        Opcode::GetGlobal => "GET_GLOBAL",
+        Opcode::SetGlobal => "SET_GLOBAL",
+        Opcode::GetLocal => "GET_LOCAL",
+        Opcode::SetLocal => "SET_LOCAL",
+        Opcode::Jump => "JUMP",
+        Opcode::JumpIfFalse => "JUMP_IF_FALSE",
+        Opcode::Push => "PUSH",
+        Opcode::Loop => "LOOP",
+        Opcode::Call => "CALL",
+        Opcode::Closure => "CLOSURE",
+        Opcode::GetUpvalue => "GET_UPVALUE",
+        Opcode::SetUpvalue => "SET_UPVALUE",
+        Opcode::CloseUpvalue => "CLOSE_UPVALUE",
+        Opcode::Class => "CLASS",
+        Opcode::GetProperty => "GET_PROPERTY",
+        Opcode::SetProperty => "SET_PROPERTY",
+        Opcode::Method => "METHOD",
+        Opcode::Inherit => "INHERIT",
+        Opcode::GetSuper => "GET_SUPER",
+        Opcode::ConstantLong => "CONSTANT_LONG",
+        Opcode::DefineGlobalLong => "DEFINE_GLOBAL_LONG",
+        Opcode::GetGlobalLong => "GET_GLOBAL_LONG",
+        Opcode::SetGlobalLong => "SET_GLOBAL_LONG",
+        Opcode::ClosureLong => "CLOSURE_LONG",
+        Opcode::ClassLong => "CLASS_LONG",
+        Opcode::GetPropertyLong => "GET_PROPERTY_LONG",
+        Opcode::SetPropertyLong => "SET_PROPERTY_LONG",
+        Opcode::MethodLong => "METHOD_LONG",
+        Opcode::GetSuperLong => "GET_SUPER_LONG",
+    }
 }
 
-/// Disassemble a CONSTANT opcode
-fn disassemble_constant(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.code[offset + 1].clone() as usize;
-    print!("{:<16} {:>4} '", name, constant);
-    let value = &chunk.constants[constant];
-    println!("{value}'");
-    offset + 2
+/// Writes the listing of a whole chunk: its constant pool, functions listed the same way inside
+/// `.function` blocks, then one instruction per line. The listing assembles back into the chunk.
+pub fn write_chunk(out: &mut impl fmt::Write, chunk: &Chunk, name: &str) -> fmt::Result {
+    writeln!(out, "== {name} ==")?;
+    for constant in &chunk.constants {
+        match constant {
+            Constant::Function(function) => {
+                writeln!(out, ".function {} {} {}", function.name, function.arity, function.upvalue_count)?;
+                write_chunk(out, &function.chunk, &function.name)?;
+                writeln!(out, ".end")?;
+            },
+            _ => writeln!(out, ".const {}", listed_constant(constant))?,
+        }
+    }
+    for instruction in disassemble(chunk) {
+        write_instruction(out, chunk, &instruction)?;
+    }
+    Ok(())
 }
 
-fn disassemble_get_local(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.code[offset + 1].clone() as usize;
-    println!("{:<16} {:>4} '", name, constant);
-    offset + 2
+/// A constant as shown in listings. Strings are quoted and escaped, so "42" can't be taken for 42
+pub(crate) fn listed_constant(constant: &Constant) -> String {
+    match constant {
+        Constant::String(string) => format!("{:?}", string),
+        _ => constant.to_string(),
+    }
 }
 
-fn disassemble_short_jump(name: &str, sign: i8, chunk: &Chunk, offset: usize) -> usize {
-    let byte1 = chunk.code[offset + 1].clone() as usize;
-    let byte2 = chunk.code[offset + 2].clone() as usize;
-    let jump = byte1 << 8 | byte2;
-    let j2 = (sign as i32) * (jump as i32);
-    if let Some(total_jump) = add_offset(offset + 3, j2) {
-        println!("{:<16} {:>4} -> {:4}'", name, offset, total_jump);
+/// Writes the listing of a single instruction, the chunk is needed to tell if its line changed
+pub fn write_instruction(out: &mut impl fmt::Write, chunk: &Chunk, instruction: &Instruction) -> fmt::Result {
+    let offset = instruction.offset;
+    write!(out, "{:04} ", offset)?;
+
+    // If this instruction is in the same line as the previous don't show a new line show a |
+    // Else, if it has changed, show the line number.
+    if offset > 0 && instruction.line == chunk.get_line(offset - 1) {
+        write!(out, "   | ")?;
     } else {
-        println!("{:<16} {:>4} -> ???'", name, offset);
+        write!(out, "{:4} ", instruction.line)?;
+    }
+
+    let Some(opcode) = &instruction.opcode else {
+        let byte = chunk.read_byte(offset).map_or("past the end".to_string(), |byte| byte.to_string());
+        return writeln!(out, "Unknown opcode {byte}");
+    };
+    let name = opcode_name(opcode);
+    match instruction.operands.first() {
+        None if opcode.operand_width() > 0 => writeln!(out, "{name} <missing operand>"),
+        None => writeln!(out, "{name}"),
+        Some(Operand::Constant(index)) => {
+            let constant = match instruction.constant {
+                Some(constant) => listed_constant(constant),
+                None => "<out of range>".to_string(),
+            };
+            writeln!(out, "{:<16} {:>4} {constant}", name, index)?;
+            write_upvalues(out, instruction)
+        },
+        Some(Operand::Byte(byte)) => writeln!(out, "{:<16} {:>4} '", name, byte),
+        Some(Operand::Jump(_)) => match instruction.jump_target {
+            Some(target) => writeln!(out, "{:<16} {:>4} -> {:4}'", name, offset, target),
+            None => writeln!(out, "{:<16} {:>4} -> ???'", name, offset),
+        },
+        Some(Operand::Upvalue { .. }) => unreachable!("Upvalues only follow a closure constant"),
     }
-    offset + 3
 }
 
-fn add_offset(u: usize, i: i32) -> Option<usize> {
-    if i.is_negative() {
-        u.checked_sub(i.wrapping_abs() as u32 as usize)
-    } else {
-        u.checked_add(i as usize)
+/// Writes the pairs of bytes following a CLOSURE, one line for each captured upvalue
+fn write_upvalues(out: &mut impl fmt::Write, instruction: &Instruction) -> fmt::Result {
+    let upvalues = instruction.operands.iter().filter_map(|operand| match operand {
+        Operand::Upvalue { is_local, index } => Some((*is_local, *index)),
+        _ => None,
+    });
+    // The constant takes the 2 bytes after the opcode offset
+    for (i, (is_local, index)) in upvalues.enumerate() {
+        let kind = if is_local { "local" } else { "upvalue" };
+        writeln!(out, "{:04}    |                     {kind} {index}", instruction.offset + 2 + 2 * i)?;
+    }
+    Ok(())
+}
+
+/// Writes the listing of a chunk to anything implementing `io::Write`
+pub fn write_chunk_io(out: &mut impl std::io::Write, chunk: &Chunk, name: &str) -> std::io::Result<()> {
+    let mut listing = String::new();
+    write_chunk(&mut listing, chunk, name).expect("Writing to a String can't fail");
+    out.write_all(listing.as_bytes())
+}
+
+/// Prints the listing of a chunk to stdout
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+    let mut listing = String::new();
+    write_chunk(&mut listing, chunk, name).expect("Writing to a String can't fail");
+    print!("{listing}");
+}
+
+/// Disassemble a single instruction to stdout, returns the offset of the next one
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let Some(instruction) = decode_instruction(chunk, offset) else {
+        println!("{offset:04} <end of code>");
+        return offset;
+    };
+    let mut listing = String::new();
+    write_instruction(&mut listing, chunk, &instruction).expect("Writing to a String can't fail");
+    print!("{listing}");
+    offset + instruction.len
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::function::Function;
+
+    #[test]
+    fn test_disassemble() {
+        let mut chunk = Chunk::init();
+        chunk.write_constant(Constant::Number(1.5), 1);
+        chunk.write_opcode(Opcode::JumpIfFalse, 1);
+        chunk.write_short(1, 1);
+        chunk.write_opcode(Opcode::Pop, 2);
+        chunk.write_opcode(Opcode::Return, 2);
+
+        let instructions = disassemble(&chunk);
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(instructions[0].opcode, Some(Opcode::Constant));
+        assert_eq!(instructions[0].operands, vec![Operand::Constant(0)]);
+        assert_eq!(instructions[0].constant, Some(&Constant::Number(1.5)));
+        assert_eq!(instructions[1].offset, 2);
+        assert_eq!(instructions[1].operands, vec![Operand::Jump(1)]);
+        assert_eq!(instructions[1].jump_target, Some(6));
+        assert_eq!(instructions[2].line, 2);
+        assert_eq!(instructions[3].len, 1);
+    }
+
+    #[test]
+    fn test_closure_upvalues() {
+        let mut function = Function::init("f");
+        function.upvalue_count = 2;
+        let mut chunk = Chunk::init();
+        let index = chunk.add_constant(Constant::Function(Rc::new(function)));
+        chunk.write_indexed(Opcode::Closure, index, 1);
+        chunk.write_byte(1, 1);
+        chunk.write_byte(3, 1);
+        chunk.write_byte(0, 1);
+        chunk.write_byte(0, 1);
+
+        let instruction = decode_instruction(&chunk, 0).unwrap();
+        assert_eq!(instruction.len, 6);
+        assert_eq!(instruction.operands, vec![
+            Operand::Constant(0),
+            Operand::Upvalue { is_local: true, index: 3 },
+            Operand::Upvalue { is_local: false, index: 0 },
+        ]);
+    }
+
+    #[test]
+    fn test_decode_truncated_code() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::ConstantLong, 1);
+        chunk.write_byte(0, 1);
+
+        let instruction = decode_instruction(&chunk, 0).unwrap();
+        assert_eq!(instruction.operands, vec![]);
+        assert_eq!(instruction.len, 2);
+        assert_eq!(decode_instruction(&chunk, 2), None);
+        assert_eq!(decode_instruction(&chunk, 100), None);
+    }
+
+    #[test]
+    fn test_write_chunk() {
+        let mut chunk = Chunk::init();
+        chunk.write_constant(Constant::String("a".into()), 1);
+        chunk.write_opcode(Opcode::Loop, 2);
+        chunk.write_short(5, 2);
+        chunk.write_byte(255, 2);
+        chunk.write_opcode(Opcode::GetLocal, 3);
+
+        let mut listing = String::new();
+        write_chunk(&mut listing, &chunk, "test").unwrap();
+        assert_eq!(listing, "\
+== test ==
+.const \"a\"
+0000    1 CONSTANT            0 \"a\"
+0002    2 LOOP                2 ->    0'
+0005    | Unknown opcode 255
+0006    3 GET_LOCAL <missing operand>
+");
     }
 }
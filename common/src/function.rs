@@ -0,0 +1,38 @@
+use std::fmt;
+
+use crate::chunk::Chunk;
+
+/// A compiled function, every function body gets its own chunk.
+/// The top-level script is compiled into a function with an empty name.
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub arity: u8,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: String,
+}
+
+impl Function {
+    pub fn init(name: &str) -> Function {
+        Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::init(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.name.is_empty()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_script() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Deduplicates strings so that equal strings share one allocation.
+/// The compiler interns every name and literal, the VM then reuses those allocations for its strings.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    pub fn init() -> Interner {
+        Interner {
+            strings: HashSet::new(),
+        }
+    }
+
+    /// The shared copy of the string, created on first use
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Rc<str> = Rc::from(s);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_shares_allocation() {
+        let mut interner = Interner::init();
+        let a = interner.intern("hello");
+        let b = interner.intern(&String::from("hello"));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[test]
+    fn test_intern_distinct_strings() {
+        let mut interner = Interner::init();
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+        assert!(!Rc::ptr_eq(&a, &b));
+        assert_eq!(interner.len(), 2);
+    }
+}
@@ -1,14 +1,25 @@
 use std::fmt;
+use std::rc::Rc;
+
+use crate::function::Function;
 
 pub mod opcode;
 pub mod disassembler;
 pub mod chunk;
 pub mod utils;
+pub mod function;
+pub mod interner;
+pub mod bytecode;
+pub mod verifier;
+pub mod assembler;
+pub mod cfg;
+pub mod span;
 
 #[derive(Debug, PartialEq)]
 pub enum Constant {
     Number(f64),
-    String(String),
+    String(Rc<str>),
+    Function(Rc<Function>),
 }
 
 impl fmt::Display for Constant {
@@ -16,44 +27,7 @@ impl fmt::Display for Constant {
         match self {
             Constant::Number(number) => write!(f, "{}", number),
             Constant::String(string) => write!(f, "{}", string),
-        }
-    }
-}
-
-#[derive(Debug,PartialEq, Eq)]
-pub enum ValueType {
-    Nil,
-    Number,
-    Bool,
-    String,
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum Value {
-    Nil,
-    Number(f64),
-    Bool(bool),
-    String(String),
-}
-
-impl Value {
-    pub fn is_falsey(&self) -> bool {
-        match self {
-            Value::Nil => true,
-            Value::Number(n) => *n == 0.0,
-            Value::Bool(b) => *b == false,
-            Value::String(s) => s.is_empty(),
-        }
-    }
-}
-
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Number(n)=> write!(f, "{}", n),
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::String(s) => write!(f, "\"{}\"", s),
+            Constant::Function(function) => write!(f, "{}", function),
         }
     }
 }
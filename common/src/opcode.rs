@@ -27,11 +27,70 @@ pub enum Opcode {
     Jump = 21,
     JumpIfFalse = 22,
     Push = 23,
+    Loop = 24,
+    Call = 25,
+    Closure = 26,
+    GetUpvalue = 27,
+    SetUpvalue = 28,
+    CloseUpvalue = 29,
+    Class = 30,
+    GetProperty = 31,
+    SetProperty = 32,
+    Method = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    // Long forms of the instructions taking a constant index, with a 24 bits operand
+    ConstantLong = 36,
+    DefineGlobalLong = 37,
+    GetGlobalLong = 38,
+    SetGlobalLong = 39,
+    ClosureLong = 40,
+    ClassLong = 41,
+    GetPropertyLong = 42,
+    SetPropertyLong = 43,
+    MethodLong = 44,
+    GetSuperLong = 45,
 }
 
 impl Opcode {
-    pub fn from_byte(byte: u8) -> Opcode {
-        let maybe_opcode = num_traits::FromPrimitive::from_u8(byte);
-        maybe_opcode.expect("Expected {byte} to be an opcode but it is not")
+    /// Returns None if the byte isn't a known opcode
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        num_traits::FromPrimitive::from_u8(byte)
+    }
+
+    /// Number of operand bytes following the opcode. Closure and ClosureLong are also followed by
+    /// two bytes for each upvalue of its function
+    pub fn operand_width(&self) -> usize {
+        match self {
+            Opcode::Constant | Opcode::DefineGlobal | Opcode::GetGlobal | Opcode::SetGlobal
+            | Opcode::GetLocal | Opcode::SetLocal | Opcode::Push | Opcode::Call | Opcode::Closure
+            | Opcode::GetUpvalue | Opcode::SetUpvalue | Opcode::Class | Opcode::GetProperty
+            | Opcode::SetProperty | Opcode::Method | Opcode::GetSuper => 1,
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop => 2,
+            Opcode::ConstantLong | Opcode::DefineGlobalLong | Opcode::GetGlobalLong | Opcode::SetGlobalLong
+            | Opcode::ClosureLong | Opcode::ClassLong | Opcode::GetPropertyLong | Opcode::SetPropertyLong
+            | Opcode::MethodLong | Opcode::GetSuperLong => 3,
+            Opcode::Return | Opcode::Negate | Opcode::Add | Opcode::Subtract | Opcode::Multiply
+            | Opcode::Divide | Opcode::Nil | Opcode::True | Opcode::False | Opcode::Not | Opcode::Equal
+            | Opcode::Greater | Opcode::Less | Opcode::Print | Opcode::Pop | Opcode::CloseUpvalue
+            | Opcode::Inherit => 0,
+        }
+    }
+
+    /// The variant taking a 24 bits constant index, for the instructions that have one
+    pub fn long_form(&self) -> Option<Opcode> {
+        match self {
+            Opcode::Constant => Some(Opcode::ConstantLong),
+            Opcode::DefineGlobal => Some(Opcode::DefineGlobalLong),
+            Opcode::GetGlobal => Some(Opcode::GetGlobalLong),
+            Opcode::SetGlobal => Some(Opcode::SetGlobalLong),
+            Opcode::Closure => Some(Opcode::ClosureLong),
+            Opcode::Class => Some(Opcode::ClassLong),
+            Opcode::GetProperty => Some(Opcode::GetPropertyLong),
+            Opcode::SetProperty => Some(Opcode::SetPropertyLong),
+            Opcode::Method => Some(Opcode::MethodLong),
+            Opcode::GetSuper => Some(Opcode::GetSuperLong),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,18 @@
+/// Where something is in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Line of the start, starting at 1
+    pub line: usize,
+    /// Column of the start in characters, starting at 1
+    pub column: usize,
+    /// Byte range in the source
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// From the start of this span to the end of the other one
+    pub fn to(self, other: Span) -> Span {
+        Span { end: self.end.max(other.end), ..self }
+    }
+}
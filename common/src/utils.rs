@@ -1,6 +1,6 @@
 #[allow(unused_macros)]
+#[allow(clippy::module_inception)]
 #[macro_use]
-
 pub mod utils {
 
     #[macro_export]
@@ -13,7 +13,7 @@ pub mod utils {
     #[macro_export]
     macro_rules! write_string {
     ($vm:expr, $value:expr) => {
-        $vm.chunk.write_constant(Constant::String($value.to_string()), 123);
+        $vm.chunk.write_constant(Constant::String($value.into()), 123);
     };
     }
 
@@ -27,8 +27,7 @@ pub mod utils {
     #[macro_export]
     macro_rules! run_and_expect {
     ($vm:expr, $expected:expr) => {
-        let (status, Some(value)) = $vm.run() else { panic!("failed to execute vm") };
-        assert_eq!(status, super::InterpretResult::OK);
+        let value = $vm.run().expect("failed to execute vm");
         assert_eq!(value, $expected);
     };
     }
@@ -36,9 +35,8 @@ pub mod utils {
     #[macro_export]
     macro_rules! run_and_expect_str {
     ($vm:expr, $expected:expr) => {
-        let (status, Some(value)) = $vm.run() else { panic!("failed to execute vm") };
-        assert_eq!(status, super::InterpretResult::OK);
-        assert_eq!(value, Value::String($expected.to_string()));
+        let value = $vm.run().expect("failed to execute vm");
+        assert_eq!($vm.heap.as_string(value), Some($expected));
     };
     }
 }
@@ -0,0 +1,407 @@
+//! Checks that a chunk is safe to run before handing it to the VM, for chunks
+//! that didn't come straight from the compiler, like `.loxc` files.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::function::Function;
+use crate::opcode::Opcode;
+use crate::Constant;
+
+/// A problem found in a chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// Name of the function whose chunk has the problem, empty for the script
+    pub function: String,
+    /// Offset of the instruction with the problem
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "{:04} in script: {}", self.offset, self.message)
+        } else {
+            write!(f, "{:04} in {}(): {}", self.offset, self.function, self.message)
+        }
+    }
+}
+
+/// Verifies the chunk of a script and of every function in it, returning every problem found
+pub fn verify(chunk: &Chunk) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    // The script doesn't reserve a stack slot for itself
+    verify_chunk(chunk, "", 0, 0, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn verify_function(function: &Function, errors: &mut Vec<VerifyError>) {
+    // Slot 0 holds the function or the receiver, followed by the arguments
+    let initial_depth = function.arity as usize + 1;
+    verify_chunk(&function.chunk, &function.name, initial_depth, function.upvalue_count, errors);
+}
+
+/// An instruction decoded from the code
+struct Instruction {
+    offset: usize,
+    opcode: Opcode,
+    // Constant index, slot, argument count or jump distance, depending on the opcode
+    operand: usize,
+    // (is_local, index) pairs following a Closure
+    upvalues: Vec<(u8, u8)>,
+    len: usize,
+}
+
+impl Instruction {
+    /// Values the instruction needs on the stack, and values it leaves in their place
+    fn stack_effect(&self) -> (usize, usize) {
+        match self.opcode {
+            Opcode::Constant | Opcode::ConstantLong | Opcode::Nil | Opcode::True | Opcode::False
+            | Opcode::GetGlobal | Opcode::GetGlobalLong | Opcode::GetLocal | Opcode::GetUpvalue
+            | Opcode::Push | Opcode::Closure | Opcode::ClosureLong | Opcode::Class | Opcode::ClassLong => (0, 1),
+            Opcode::Pop | Opcode::Print | Opcode::DefineGlobal | Opcode::DefineGlobalLong
+            | Opcode::CloseUpvalue | Opcode::Return => (1, 0),
+            Opcode::Negate | Opcode::Not | Opcode::SetGlobal | Opcode::SetGlobalLong | Opcode::SetLocal
+            | Opcode::SetUpvalue | Opcode::GetProperty | Opcode::GetPropertyLong | Opcode::JumpIfFalse => (1, 1),
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::Equal
+            | Opcode::Greater | Opcode::Less | Opcode::SetProperty | Opcode::SetPropertyLong | Opcode::Method
+            | Opcode::MethodLong | Opcode::Inherit | Opcode::GetSuper | Opcode::GetSuperLong => (2, 1),
+            // The callee and its arguments are replaced by the returned value
+            Opcode::Call => (self.operand + 1, 1),
+            Opcode::Jump | Opcode::Loop => (0, 0),
+        }
+    }
+
+    /// Where a jump goes, None if it goes before the start of the code
+    fn jump_target(&self) -> Option<usize> {
+        let next = self.offset + self.len;
+        match self.opcode {
+            Opcode::Jump | Opcode::JumpIfFalse => Some(next + self.operand),
+            Opcode::Loop => next.checked_sub(self.operand),
+            _ => None,
+        }
+    }
+}
+
+struct ChunkVerifier<'a> {
+    chunk: &'a Chunk,
+    function: &'a str,
+    errors: &'a mut Vec<VerifyError>,
+}
+
+impl ChunkVerifier<'_> {
+    fn error(&mut self, offset: usize, message: String) {
+        self.errors.push(VerifyError { function: self.function.to_string(), offset, message });
+    }
+
+    /// Splits the code in instructions, None if it can't be decoded until the end
+    fn decode(&mut self) -> Option<Vec<Instruction>> {
+        let code = &self.chunk.code;
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let Some(opcode) = Opcode::from_byte(code[offset]) else {
+                self.error(offset, format!("Unknown opcode {}", code[offset]));
+                return None;
+            };
+            let width = opcode.operand_width();
+            let Some(operand_bytes) = code.get(offset + 1..offset + 1 + width) else {
+                self.error(offset, format!("{opcode} is missing its operand"));
+                return None;
+            };
+            let operand = operand_bytes.iter().fold(0, |operand, byte| operand << 8 | *byte as usize);
+            let mut len = 1 + width;
+
+            let mut upvalues = Vec::new();
+            if matches!(opcode, Opcode::Closure | Opcode::ClosureLong) {
+                // The operand tells how many upvalue pairs follow, without it the rest can't be decoded
+                let Some(Constant::Function(function)) = self.chunk.constants.get(operand) else {
+                    self.error(offset, format!("{opcode} operand {operand} is not a function constant"));
+                    return None;
+                };
+                let Some(pairs) = code.get(offset + len..offset + len + 2 * function.upvalue_count) else {
+                    self.error(offset, format!("{opcode} is missing the upvalues of {function}"));
+                    return None;
+                };
+                upvalues = pairs.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+                len += pairs.len();
+            }
+
+            instructions.push(Instruction { offset, opcode, operand, upvalues, len });
+            offset += len;
+        }
+        Some(instructions)
+    }
+
+    /// Checks the operands that don't depend on the state of the stack
+    fn check_operands(&mut self, instruction: &Instruction, upvalue_count: usize, boundaries: &HashMap<usize, usize>) {
+        let offset = instruction.offset;
+        let operand = instruction.operand;
+        match instruction.opcode {
+            Opcode::Constant | Opcode::ConstantLong if operand >= self.chunk.constants.len() =>
+                self.error(offset, format!("Constant {operand} out of range")),
+            Opcode::DefineGlobal | Opcode::DefineGlobalLong | Opcode::GetGlobal | Opcode::GetGlobalLong
+            | Opcode::SetGlobal | Opcode::SetGlobalLong | Opcode::Class | Opcode::ClassLong
+            | Opcode::GetProperty | Opcode::GetPropertyLong | Opcode::SetProperty | Opcode::SetPropertyLong
+            | Opcode::Method | Opcode::MethodLong | Opcode::GetSuper | Opcode::GetSuperLong => {
+                match self.chunk.constants.get(operand) {
+                    Some(Constant::String(_)) => {},
+                    Some(_) => self.error(offset, format!("{} operand {operand} is not a string constant", instruction.opcode)),
+                    None => self.error(offset, format!("Constant {operand} out of range")),
+                }
+            },
+            Opcode::GetUpvalue | Opcode::SetUpvalue if operand >= upvalue_count =>
+                self.error(offset, format!("Upvalue {operand} out of range, the function has {upvalue_count}")),
+            Opcode::Closure | Opcode::ClosureLong => {
+                for (is_local, index) in &instruction.upvalues {
+                    match is_local {
+                        0 if *index as usize >= upvalue_count =>
+                            self.error(offset, format!("Captured upvalue {index} out of range, the function has {upvalue_count}")),
+                        0 | 1 => {},
+                        _ => self.error(offset, format!("Invalid upvalue kind {is_local}, expected 0 or 1")),
+                    }
+                }
+            },
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop => {
+                // Jumping right past the last instruction is fine, it's the implicit return
+                match instruction.jump_target() {
+                    Some(target) if target == self.chunk.code.len() || boundaries.contains_key(&target) => {},
+                    Some(target) => self.error(offset, format!("Jump target {target} is not the start of an instruction")),
+                    None => self.error(offset, "Jump target is before the start of the code".to_string()),
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Follows every path through the code making sure each instruction has the values it needs
+    /// on the stack, and that paths joining at an instruction agree on the stack depth
+    fn check_stack(&mut self, instructions: &[Instruction], initial_depth: usize, boundaries: &HashMap<usize, usize>) {
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        let mut pending = vec![(0, initial_depth)];
+        while let Some((index, depth)) = pending.pop() {
+            let instruction = &instructions[index];
+            let offset = instruction.offset;
+            if let Some(known) = depths[index] {
+                if known != depth {
+                    self.error(offset, format!("Stack depth is {depth} on one path and {known} on another"));
+                }
+                continue;
+            }
+            depths[index] = Some(depth);
+
+            let (needed, left) = instruction.stack_effect();
+            if depth < needed {
+                let opcode = &instruction.opcode;
+                self.error(offset, format!("{opcode} needs {needed} values but the stack has {depth}"));
+                continue;
+            }
+            match instruction.opcode {
+                Opcode::GetLocal | Opcode::SetLocal if instruction.operand >= depth =>
+                    self.error(offset, format!("Local slot {} is past the top of the stack ({depth} values)", instruction.operand)),
+                Opcode::Closure | Opcode::ClosureLong => {
+                    for (_, index) in instruction.upvalues.iter().filter(|(is_local, _)| *is_local == 1) {
+                        if *index as usize >= depth {
+                            self.error(offset, format!("Captured local slot {index} is past the top of the stack ({depth} values)"));
+                        }
+                    }
+                },
+                _ => {},
+            }
+
+            let depth = depth - needed + left;
+            let next = offset + instruction.len;
+            let successors = match instruction.opcode {
+                Opcode::Return => vec![],
+                Opcode::Jump | Opcode::Loop => vec![instruction.jump_target()],
+                Opcode::JumpIfFalse => vec![Some(next), instruction.jump_target()],
+                _ => vec![Some(next)],
+            };
+            // Targets past the end are the implicit return, invalid targets were already reported
+            for successor in successors.into_iter().flatten() {
+                if let Some(&successor) = boundaries.get(&successor) {
+                    pending.push((successor, depth));
+                }
+            }
+        }
+    }
+}
+
+fn verify_chunk(chunk: &Chunk, function: &str, initial_depth: usize, upvalue_count: usize, errors: &mut Vec<VerifyError>) {
+    let mut verifier = ChunkVerifier { chunk, function, errors };
+    // Lines are looked up in the spans by offset, they must start with the code and be in order
+    let starts_with_code = chunk.code.is_empty() || chunk.spans.first().is_some_and(|(offset, _)| *offset == 0);
+    if !starts_with_code || !chunk.spans.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+        verifier.error(0, "Span table doesn't cover the code in order".to_string());
+    }
+
+    if let Some(instructions) = verifier.decode() {
+        // Offset of each instruction to its position in the list
+        let boundaries: HashMap<usize, usize> = instructions.iter().enumerate()
+            .map(|(index, instruction)| (instruction.offset, index))
+            .collect();
+        for instruction in &instructions {
+            verifier.check_operands(instruction, upvalue_count, &boundaries);
+        }
+        if !instructions.is_empty() {
+            verifier.check_stack(&instructions, initial_depth, &boundaries);
+        }
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            verify_function(function, errors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn messages(chunk: &Chunk) -> Vec<String> {
+        verify(chunk).unwrap_err().iter().map(|error| error.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_chunk() {
+        let mut chunk = Chunk::init();
+        let name = chunk.add_constant(Constant::String("a".into()));
+        chunk.write_constant(Constant::Number(1.0), 1);
+        chunk.write_indexed(Opcode::DefineGlobal, name, 1);
+        chunk.write_indexed(Opcode::GetGlobal, name, 2);
+        chunk.write_opcode(Opcode::JumpIfFalse, 2);
+        chunk.write_short(2, 2);
+        chunk.write_opcode(Opcode::Pop, 2);
+        chunk.write_opcode(Opcode::Nil, 2);
+        chunk.write_opcode(Opcode::Return, 3);
+        assert_eq!(verify(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn test_spans_out_of_order() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_opcode(Opcode::Return, 2);
+        chunk.spans.reverse();
+        assert_eq!(messages(&chunk), vec!["0000 in script: Span table doesn't cover the code in order"]);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_byte(200, 1);
+        assert_eq!(messages(&chunk), vec!["0001 in script: Unknown opcode 200"]);
+    }
+
+    #[test]
+    fn test_missing_operand() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Jump, 1);
+        chunk.write_byte(0, 1);
+        assert_eq!(messages(&chunk), vec!["0000 in script: Jump is missing its operand"]);
+    }
+
+    #[test]
+    fn test_constant_out_of_range() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Constant, 1);
+        chunk.write_byte(3, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(messages(&chunk), vec!["0000 in script: Constant 3 out of range"]);
+    }
+
+    #[test]
+    fn test_global_name_must_be_a_string() {
+        let mut chunk = Chunk::init();
+        let number = chunk.add_constant(Constant::Number(1.0));
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_indexed(Opcode::DefineGlobal, number, 1);
+        assert_eq!(messages(&chunk), vec!["0001 in script: DefineGlobal operand 0 is not a string constant"]);
+    }
+
+    #[test]
+    fn test_jump_into_an_operand() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Jump, 1);
+        chunk.write_short(1, 1);
+        chunk.write_constant(Constant::Number(1.0), 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(messages(&chunk), vec!["0000 in script: Jump target 4 is not the start of an instruction"]);
+    }
+
+    #[test]
+    fn test_jump_past_the_end() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Jump, 1);
+        chunk.write_short(10, 1);
+        chunk.write_opcode(Opcode::Loop, 1);
+        chunk.write_short(10, 1);
+        assert_eq!(messages(&chunk), vec![
+            "0000 in script: Jump target 13 is not the start of an instruction",
+            "0003 in script: Jump target is before the start of the code",
+        ]);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut chunk = Chunk::init();
+        chunk.write_constant(Constant::Number(1.0), 1);
+        chunk.write_opcode(Opcode::Add, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(messages(&chunk), vec!["0002 in script: Add needs 2 values but the stack has 1"]);
+    }
+
+    #[test]
+    fn test_unbalanced_branches() {
+        // if (nil) 1; with the then branch forgetting to pop its value
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_opcode(Opcode::JumpIfFalse, 1);
+        chunk.write_short(2, 1);
+        chunk.write_opcode(Opcode::Push, 1);
+        chunk.write_byte(1, 1);
+        chunk.write_opcode(Opcode::Pop, 1);
+        assert_eq!(messages(&chunk), vec!["0006 in script: Stack depth is 2 on one path and 1 on another"]);
+    }
+
+    #[test]
+    fn test_local_past_the_top() {
+        let mut chunk = Chunk::init();
+        chunk.write_opcode(Opcode::Nil, 1);
+        chunk.write_opcode(Opcode::GetLocal, 1);
+        chunk.write_byte(1, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(messages(&chunk), vec!["0001 in script: Local slot 1 is past the top of the stack (1 values)"]);
+    }
+
+    #[test]
+    fn test_reports_every_problem_in_nested_functions() {
+        let mut function = Function::init("f");
+        function.arity = 1;
+        function.chunk.write_opcode(Opcode::GetLocal, 1);
+        function.chunk.write_byte(1, 1);
+        function.chunk.write_opcode(Opcode::GetUpvalue, 1);
+        function.chunk.write_byte(0, 1);
+        function.chunk.write_opcode(Opcode::Return, 1);
+
+        let mut chunk = Chunk::init();
+        let function = chunk.add_constant(Constant::Function(Rc::new(function)));
+        chunk.write_indexed(Opcode::Closure, function, 1);
+        chunk.write_opcode(Opcode::Pop, 1);
+        chunk.write_opcode(Opcode::Pop, 1);
+        assert_eq!(messages(&chunk), vec![
+            "0003 in script: Pop needs 1 values but the stack has 0",
+            "0002 in f(): Upvalue 0 out of range, the function has 0",
+        ]);
+    }
+}
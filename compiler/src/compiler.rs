@@ -1,7 +1,13 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
 use num_derive::FromPrimitive;
 
-use common::{chunk::Chunk, Constant, disassembler::disassemble_chunk, opcode::Opcode};
+use common::{chunk::{Chunk, MAX_CONSTANTS}, Constant, disassembler::write_chunk, function::Function, interner::Interner, opcode::Opcode};
+use common::span::Span;
 
+use crate::diagnostic::{Diagnostic, Lint, LintLevel, Severity};
 use crate::scanner;
 use crate::scanner::{Token, TokenType};
 
@@ -25,20 +31,76 @@ enum Precedence {
 /// Represents a single row in the parsing table
 struct ParseRule {
     prefix: Option<fn(&mut Parser, bool)>,
-    infix: Option<fn(&mut Parser)>,
+    infix: Option<fn(&mut Parser, bool)>,
     precedence: Precedence
 }
 
 struct Local {
     name: Token,
-    depth: i16 // Scope depth of the block where the variable was defined
+    depth: i16, // Scope depth of the block where the variable was defined
+    is_captured: bool, // Whether a closure captures it, so it has to be moved to the heap
+    is_read: bool, // Whether its value is ever used, capturing it counts as using it
+}
+
+/// A variable captured by a closure from an enclosing function
+struct Upvalue {
+    index: u8,
+    // Whether it captures a local of the immediately enclosing function
+    // or one of the enclosing function's own upvalues
+    is_local: bool,
+}
+
+/// State of a class declaration being compiled
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Compilation state of a single function.
+/// The parser keeps a stack of these, the innermost function being compiled is the last one.
+struct Compiler {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i16
+}
+
+impl Compiler {
+    fn init(function_type: FunctionType, name: &str) -> Compiler {
+        let mut locals = Vec::new();
+        match function_type {
+            // Slot 0 of a method's call frame holds the receiver, accessed through `this`
+            FunctionType::Method | FunctionType::Initializer =>
+                locals.push(Local { name: Token::synthetic("this"), depth: 0, is_captured: false, is_read: false }),
+            // Slot 0 of a call frame holds the function being called,
+            // reserve it with a name that can never be referenced
+            FunctionType::Function =>
+                locals.push(Local { name: Token::synthetic(""), depth: 0, is_captured: false, is_read: false }),
+            FunctionType::Script => {},
+        }
+        Compiler {
+            function: Function::init(name),
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
 }
 
 fn parse_rule(token_type: &TokenType) -> ParseRule {
     use TokenType::*;
     match token_type {
         LeftParen =>
-            ParseRule { prefix: Some(Parser::grouping), infix: None, precedence: Precedence::None },
+            ParseRule { prefix: Some(Parser::grouping), infix: Some(Parser::call), precedence: Precedence::Call },
         Bang =>
             ParseRule { prefix: Some(Parser::unary), infix: None, precedence: Precedence::None },
         Minus =>
@@ -57,8 +119,16 @@ fn parse_rule(token_type: &TokenType) -> ParseRule {
             ParseRule { prefix: None, infix: Some(Parser::binary), precedence: Precedence::Equality },
         Greater | GreaterEqual | Less | LessEqual =>
             ParseRule { prefix: None, infix: Some(Parser::binary), precedence: Precedence::Comparison },
+        Dot =>
+            ParseRule { prefix: None, infix: Some(Parser::dot), precedence: Precedence::Call },
         Identifier =>
             ParseRule { prefix: Some(Parser::variable), infix: None, precedence: Precedence::None },
+        This =>
+            ParseRule { prefix: Some(Parser::this), infix: None, precedence: Precedence::None },
+        Super =>
+            ParseRule { prefix: Some(Parser::super_), infix: None, precedence: Precedence::None },
+        Fun =>
+            ParseRule { prefix: Some(Parser::function_literal), infix: None, precedence: Precedence::None },
         And =>
             ParseRule { prefix: None, infix: Some(Parser::and), precedence: Precedence::And },
         Or =>
@@ -68,47 +138,90 @@ fn parse_rule(token_type: &TokenType) -> ParseRule {
     }
 }
 
-pub fn compile(source: &str) -> Option<Chunk> {
+/// What to print and check while compiling
+#[derive(Default)]
+pub struct CompileOptions<'a> {
+    /// Where to print the listing of every compiled function, nothing is printed if None
+    pub print_code: Option<&'a mut dyn Write>,
+    /// Where to collect warnings, they are dropped if None
+    pub warnings: Option<&'a mut Vec<Diagnostic>>,
+    /// Lints missing from the map warn
+    pub lints: HashMap<Lint, LintLevel>,
+}
+
+/// Compiles a script, or returns every error found in it
+pub fn compile(source: &str) -> Result<Chunk, Vec<Diagnostic>> {
+    compile_with(source, CompileOptions::default())
+}
+
+pub fn compile_with(source: &str, options: CompileOptions) -> Result<Chunk, Vec<Diagnostic>> {
     let mut parser = Parser::init(source);
-    //let mut compiler = Compiler::init();
+    parser.lints = options.lints;
 
     parser.advance();
-    while !parser.tmatch(TokenType::EOF) {
-        parser.parse_declaration();
-    }
+    parser.parse_declarations_until(TokenType::EOF);
     parser.consume(TokenType::EOF, "Expected end of expression");
 
+    let (script, _) = parser.end_compiler();
+    if let (Some(out), false) = (options.print_code, parser.had_error) {
+        // Functions are listed along with the constants of the chunks they are declared in
+        let mut listing = String::new();
+        write_chunk(&mut listing, &script.chunk, "code").expect("Writing to a String can't fail");
+        // The listing is only a debugging aid, failing to print it doesn't fail the compilation
+        let _ = out.write_all(listing.as_bytes());
+    }
+
+    // Lints find things at the end of scopes, put them back in source order
+    parser.diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    let (errors, warnings) = parser.diagnostics.into_iter()
+        .partition(|diagnostic| diagnostic.severity == Severity::Error);
+    if let Some(out) = options.warnings {
+        out.extend(warnings);
+    }
     if parser.had_error {
-        None
+        Err(errors)
     } else {
-        disassemble_chunk(&parser.chunk, "code");
-        Some(parser.chunk)
+        Ok(script.chunk)
     }
 }
 
 struct Parser {
-    chunk: Chunk,
     scanner: scanner::Scanner,
     current: Option<Token>,
     previous: Option<Token>,
     had_error: bool,
     panic_mode: bool,
-    // compiler stuff
-    locals: Vec<Local>,
-    scope_depth: i16
+    // Errors and warnings
+    diagnostics: Vec<Diagnostic>,
+    lints: HashMap<Lint, LintLevel>,
+    // Where each global declared so far was first declared, to find locals shadowing them
+    globals: HashMap<String, Span>,
+    compilers: Vec<Compiler>,
+    // Class declarations we are nested in, used to validate `this` and `super`
+    classes: Vec<ClassCompiler>,
+    interner: Interner,
+    // Where the expression whose infix operator is being compiled starts
+    expression_start: Span,
+    // Span of the expression being emitted, when it's wider than the previous token
+    expression_span: Option<Span>,
 }
 
 impl Parser {
     fn init(source: &str) -> Parser {
         Parser {
-            chunk: Chunk::init(),
             scanner: scanner::init_scanner(source),
             current: None,
             previous: None,
             had_error: false,
             panic_mode: false,
-            locals: Vec::new(),
-            scope_depth: 0,
+            diagnostics: Vec::new(),
+            lints: HashMap::new(),
+            globals: HashMap::new(),
+            compilers: vec![Compiler::init(FunctionType::Script, "")],
+            classes: Vec::new(),
+            interner: Interner::init(),
+            expression_start: Span::default(),
+            expression_span: None,
         }
     }
 
@@ -129,7 +242,11 @@ impl Parser {
     }
 
     fn parse_declaration(&mut self) {
-        if self.tmatch(TokenType::Var) {
+        if self.tmatch(TokenType::Class) {
+            self.parse_class_declaration();
+        } else if self.tmatch(TokenType::Fun) {
+            self.parse_function_declaration();
+        } else if self.tmatch(TokenType::Var) {
             self.parse_variable_declaration();
         } else {
             self.parse_statement();
@@ -140,8 +257,180 @@ impl Parser {
         }
     }
 
+    fn parse_class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous().clone();
+        let name_constant = self.identifier_constant(&class_name.lexeme);
+        self.declare_variable();
+
+        self.emit_indexed(Opcode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler { has_superclass: false });
+
+        if self.tmatch(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if self.previous().lexeme == class_name.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass is stored in a local named `super` so methods can capture it.
+            // Its own scope keeps sibling classes from seeing each other's superclass
+            self.begin_scope();
+            self.add_local(Token::synthetic("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_opcode(Opcode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back on the stack so methods can be bound to it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.current_type_is(TokenType::RightBrace) && !self.current_type_is(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(Opcode::Pop); // Pop the class
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous().lexeme.clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type, &name);
+        self.emit_indexed(Opcode::Method, constant);
+    }
+
+    fn parse_function_declaration(&mut self) {
+        let global = self.parse_variable_name("Expect function name.");
+        // A function can refer to itself in its body, so it's initialized right away
+        self.mark_initialized();
+        let name = self.previous().lexeme.clone();
+        self.function(FunctionType::Function, &name);
+        self.define_variable(global);
+    }
+
+    // An anonymous function used as an expression: fun (a, b) { ... }
+    fn function_literal(&mut self, _can_assign: bool) {
+        self.function(FunctionType::Function, "anonymous");
+    }
+
+    // Compiles the parameters and body of a function and leaves a closure over it on the stack
+    fn function(&mut self, function_type: FunctionType, name: &str) {
+        self.compilers.push(Compiler::init(function_type, name));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.current_type_is(TokenType::RightParen) {
+            loop {
+                if self.compiler().function.arity == u8::MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.compiler_mut().function.arity += 1;
+                }
+                let constant = self.parse_variable_name("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.tmatch(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.parse_block();
+
+        // No need to end the scope, the whole call frame is discarded on return
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Constant::Function(Rc::new(function)));
+        self.emit_indexed(Opcode::Closure, constant);
+
+        // The VM needs to know where to capture each upvalue from when creating the closure
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    // Property access and assignment: instance.field, instance.field = value
+    fn dot(&mut self, can_assign: bool) {
+        let start = self.expression_start;
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous().lexeme.clone();
+        let constant = self.identifier_constant(&name);
+
+        if can_assign && self.tmatch(TokenType::Equal) {
+            self.expression();
+            let span = start.to(self.previous().span());
+            self.emit_spanning(span, |parser| parser.emit_indexed(Opcode::SetProperty, constant));
+        } else {
+            let span = start.to(self.previous().span());
+            self.emit_spanning(span, |parser| parser.emit_indexed(Opcode::GetProperty, constant));
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` is a local variable in slot 0 of every method, it can't be assigned to
+        self.variable(false);
+    }
+
+    // super.method, resolved against the superclass of the class where the method is declared
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass =>
+                self.error("Can't use 'super' in a class with no superclass."),
+            _ => {},
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous().lexeme.clone();
+        let constant = self.identifier_constant(&name);
+
+        // The method gets bound to `this` but looked up in the superclass
+        self.named_variable(Token::synthetic("this"), false);
+        self.named_variable(Token::synthetic("super"), false);
+        self.emit_indexed(Opcode::GetSuper, constant);
+    }
+
+    // Finishes the innermost function being compiled and returns it along with its upvalues
+    fn end_compiler(&mut self) -> (Function, Vec<Upvalue>) {
+        if self.compiler().function_type != FunctionType::Script {
+            // Implicit `return nil;` at the end of every function body.
+            // The script doesn't need it, the VM stops when it runs out of code
+            self.emit_return();
+        }
+        let mut compiler = self.compilers.pop().expect("Expected a function being compiled");
+        // Its scope is never ended, the locals still in it are the parameters and top level variables.
+        // Parameters come after the reserved slot, they are part of the signature even when unused.
+        let parameters = 1 + compiler.function.arity as usize;
+        for local in compiler.locals.iter().skip(parameters) {
+            self.check_local_is_read(local);
+        }
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        (compiler.function, compiler.upvalues)
+    }
+
     fn parse_variable_declaration(&mut self) {
-        let global = self.parse_variable_name();
+        let global = self.parse_variable_name("Expected variable name");
 
         if self.tmatch(TokenType::Equal) {
             self.expression(); // var a = expr;
@@ -155,8 +444,8 @@ impl Parser {
         self.define_variable(global);
     }
 
-    fn parse_variable_name(&mut self) -> usize {
-        self.consume(TokenType::Identifier, "Expected variable name");
+    fn parse_variable_name(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
 
         self.declare_variable();
         if self.is_in_local_scope() {
@@ -164,7 +453,7 @@ impl Parser {
         }
 
         if let Some(ident_token) = self.previous.clone() {
-            self.make_constant(Constant::String(ident_token.lexeme))
+            self.identifier_constant(&ident_token.lexeme)
         } else {
             panic!("Expected previous to be an identifier")
         }
@@ -172,25 +461,48 @@ impl Parser {
 
     fn mark_initialized(&mut self) {
         if self.is_in_local_scope() {
-            let local = self.locals.last_mut().unwrap();
-            local.depth = self.scope_depth;
+            let scope_depth = self.compiler().scope_depth;
+            let local = self.compiler_mut().locals.last_mut().unwrap();
+            local.depth = scope_depth;
         }
     }
 
     fn declare_variable(&mut self) {
+        let name = self.previous.clone().unwrap();
+
         if self.is_in_global_scope() {
-            return; // We only do this for locals
+            // We only do this for locals, globals are only remembered to warn about shadowing them
+            let span = name.span();
+            self.globals.entry(name.lexeme).or_insert(span);
+            return;
         }
 
-        let name = self.previous.clone().unwrap();
-
-        if self.name_already_exists_in_scope(&name) {
-            self.error_at_current("Variable with this name already declared in this scope");
+        if let Some(previous) = self.local_in_current_scope(&name) {
+            let diagnostic = Diagnostic::error("Variable with this name already declared in this scope", name.span())
+                .with_label(previous.span(), "previously declared here");
+            self.report(diagnostic);
         } else {
+            self.check_shadowing(&name);
             self.add_local(name);
         }
     }
 
+    fn check_shadowing(&mut self, name: &Token) {
+        // The innermost variable with that name, in the enclosing scopes or functions
+        let outer = self.compilers.iter().rev()
+            .flat_map(|compiler| compiler.locals.iter().rev())
+            .find(|local| local.name.lexeme == name.lexeme && !local.name.is_synthetic())
+            .map(|local| (local.name.span(), "local"))
+            .or_else(|| self.globals.get(&name.lexeme).map(|span| (*span, "global")));
+
+        if let Some((span, kind)) = outer {
+            let message = format!("Variable '{}' shadows a {kind} variable", name.lexeme);
+            let diagnostic = Diagnostic::warning(Lint::Shadowing, &message, name.span())
+                .with_label(span, "shadowed variable declared here");
+            self.lint(diagnostic);
+        }
+    }
+
     fn variable(&mut self, can_assign: bool) {
         if let Some(name) = self.previous.clone() {
             self.named_variable(name, can_assign);
@@ -199,7 +511,7 @@ impl Parser {
         }
     }
 
-    fn and(&mut self) {
+    fn and(&mut self, _can_assign: bool) {
         // At the point this is called, the left side of the expression will be on top of the stack
         // If the value is false then we skip the right side (because it's all false)
         let end_jump = self.emit_jump(Opcode::JumpIfFalse);
@@ -211,7 +523,7 @@ impl Parser {
         self.patch_jump(end_jump);
     }
 
-    fn or(&mut self) {
+    fn or(&mut self, _can_assign: bool) {
         // Remember we have the left side result on top of the stack
         let else_jump = self.emit_jump(Opcode::JumpIfFalse);
         // If it's true we land here and we skip evaluating the right side towards the end
@@ -225,46 +537,85 @@ impl Parser {
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let (index, get_opt, set_opt) = match self.resolve_local(name.clone()) {
-            Some(local_index) => {
-                (local_index, Opcode::GetLocal, Opcode::SetLocal)
-            },
-            None => {
-                (self.chunk.write_identifier_constant(name.lexeme) as u8, Opcode::GetGlobal, Opcode::SetGlobal)
-            }
+        let current = self.compilers.len() - 1;
+        let local = self.resolve_local(current, &name);
+        let (index, get_opt, set_opt) = if let Some(local_index) = local {
+            (local_index as usize, Opcode::GetLocal, Opcode::SetLocal)
+        } else if let Some(upvalue_index) = self.resolve_upvalue(current, &name) {
+            (upvalue_index as usize, Opcode::GetUpvalue, Opcode::SetUpvalue)
+        } else {
+            (self.identifier_constant(&name.lexeme), Opcode::GetGlobal, Opcode::SetGlobal)
         };
 
         if can_assign && self.tmatch(TokenType::Equal) {
             self.expression();
-            self.emit_opcode(set_opt);
+            self.emit_indexed(set_opt, index);
         } else {
-            self.emit_opcode(get_opt);
+            if let Some(local_index) = local {
+                self.compiler_mut().locals[local_index as usize].is_read = true;
+            }
+            self.emit_indexed(get_opt, index);
         }
-
-        self.emit_byte(index);
     }
 
-    fn resolve_local(&mut self, name: Token) -> Option<u8> {
-        for (i, local) in self.locals.iter().enumerate().rev() {
-            if name.lexeme == local.name.lexeme {
-                if local.depth == -1 {
-                    self.error_at_current("Cannot read local variable in its own initializer");
+    // Looks for a local variable in the function at the given depth of the compilers stack
+    fn resolve_local(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        let found = self.compilers[compiler].locals.iter().enumerate().rev()
+            .find(|(_, local)| name.lexeme == local.name.lexeme)
+            .map(|(i, local)| (i, local.depth));
+        match found {
+            Some((i, depth)) => {
+                if depth == -1 {
+                    self.error("Cannot read local variable in its own initializer");
                 }
-                return Some(i as u8);
-            }
+                Some(i as u8)
+            },
+            None => None,
+        }
+    }
+
+    // Looks for a variable in the functions enclosing the given one.
+    // If found, it's added as an upvalue to every function in between so they can pass it along
+    fn resolve_upvalue(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        if compiler == 0 {
+            return None; // The script has no enclosing function, this is a global
+        }
+        let enclosing = compiler - 1;
+
+        if let Some(local_index) = self.resolve_local(enclosing, name) {
+            let local = &mut self.compilers[enclosing].locals[local_index as usize];
+            local.is_captured = true;
+            local.is_read = true;
+            return Some(self.add_upvalue(compiler, local_index, true));
+        }
+        if let Some(upvalue_index) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler, upvalue_index, false));
         }
         None
     }
 
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
+        // A closure that references the same variable many times only captures it once
+        if let Some(existing) = upvalues.iter().position(|u| u.index == index && u.is_local == is_local) {
+            return existing as u8;
+        }
+        if upvalues.len() == u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.compilers[compiler].upvalues.push(Upvalue { index, is_local });
+        (self.compilers[compiler].upvalues.len() - 1) as u8
+    }
+
     fn define_variable(&mut self, global_index: usize) {
-        if self.scope_depth > 0 {
+        if self.is_in_local_scope() {
             self.mark_initialized();
             // If we are inside a local scope: return,
             // there is nothing left to do, the variable is already in the stack
             return;
         }
-        self.emit_opcode(Opcode::DefineGlobal);
-        self.emit_byte(global_index as u8);
+        self.emit_indexed(Opcode::DefineGlobal, global_index);
     }
 
     fn parse_statement(&mut self) {
@@ -274,6 +625,10 @@ impl Parser {
             self.parse_return_statement();
         } else if self.tmatch(TokenType::If) {
             self.parse_if_statement();
+        } else if self.tmatch(TokenType::While) {
+            self.parse_while_statement();
+        } else if self.tmatch(TokenType::For) {
+            self.parse_for_statement();
         } else if self.tmatch(TokenType::LeftBrace) {
             self.begin_scope();
             self.parse_block();
@@ -293,6 +648,11 @@ impl Parser {
         if self.tmatch(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            if self.compiler().function_type == FunctionType::Initializer {
+                let diagnostic = Diagnostic::error("Can't return a value from an initializer.", self.previous().span())
+                    .with_help("initializers always return 'this', use 'return;' to leave early");
+                self.report(diagnostic);
+            }
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
             self.emit_opcode(Opcode::Return);
@@ -320,29 +680,127 @@ impl Parser {
         self.patch_jump(else_jump);
     }
 
+    fn parse_while_statement(&mut self) {
+        // We jump back here after every iteration to re-evaluate the condition
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_opcode(Opcode::Pop); // Pop the condition value
+        self.parse_statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_opcode(Opcode::Pop); // Pop the condition value
+    }
+
+    // for (initializer; condition; increment) body
+    // All three clauses are optional, the initializer variable is local to the loop
+    fn parse_for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.tmatch(TokenType::Semicolon) {
+            // No initializer
+        } else if self.tmatch(TokenType::Var) {
+            self.parse_variable_declaration();
+        } else {
+            self.parse_expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.tmatch(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse));
+            self.emit_opcode(Opcode::Pop); // Pop the condition value
+        }
+
+        if !self.tmatch(TokenType::RightParen) {
+            // The increment is compiled before the body but runs after it, so we jump over it
+            // into the body, and the body loops back to the increment instead of the condition
+            let body_jump = self.emit_jump(Opcode::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_opcode(Opcode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.parse_statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_opcode(Opcode::Pop); // Pop the condition value
+        }
+        self.end_scope();
+    }
+
+    // Emits a backwards jump to loop_start
+    fn emit_loop(&mut self, loop_start: usize) {
+        // +3 to also jump back over the LOOP instruction itself and its operands
+        let offset = self.current_chunk().code.len() - loop_start + 3;
+        if offset > u16::MAX as usize {
+            // Nothing is written, the chunk won't run anyway and a truncated jump would go to the wrong place
+            self.error("Loop body too large");
+            return;
+        }
+        self.emit_opcode(Opcode::Loop);
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
     fn emit_jump(&mut self, opcode: Opcode) -> usize {
         self.emit_opcode(opcode);
         self.emit_byte(0xff); // Placeholder jump address
         self.emit_byte(0xff);
-        self.chunk.code.len() - 2 // Return the address of the jump opcode
+        self.current_chunk().code.len() - 2 // Return the address of the jump opcode
     }
 
     // Goes back to a jump instruction and patches-in the new jump address
     fn patch_jump(&mut self, offset: usize) {
         // Adjust for the 2 bytes in the jump address, we need the opcode address
-        let jump = self.chunk.code.len() - offset - 2;
+        let jump = self.current_chunk().code.len() - offset - 2;
         if jump > u16::MAX as usize {
-            self.error_at_current("Too much code to jump over");
+            self.error("Too much code to jump over");
         }
-        self.chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.chunk.code[offset + 1] = (jump & 0xff) as u8;
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
     fn parse_block(&mut self) {
-        while !self.current_type_is(TokenType::RightBrace) && !self.current_type_is(TokenType::EOF) {
+        self.parse_declarations_until(TokenType::RightBrace);
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    // Compiles the declarations of a block or of the script, warning about the ones after a return
+    fn parse_declarations_until(&mut self, end: TokenType) {
+        let mut return_span = None;
+        let mut unreachable_start = None;
+        while !self.current_type_is(end.clone()) && !self.current_type_is(TokenType::EOF) {
+            let start = self.current.as_ref().unwrap().span();
+            if return_span.is_some() && unreachable_start.is_none() {
+                unreachable_start = Some(start);
+            }
+            if self.current_type_is(TokenType::Return) {
+                return_span = return_span.or(Some(start));
+            }
             self.parse_declaration();
         }
-        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+
+        if let (Some(return_span), Some(start)) = (return_span, unreachable_start) {
+            let diagnostic = Diagnostic::warning(Lint::UnreachableCode, "Unreachable code", start.to(self.previous().span()))
+                .with_label(return_span, "any code following this return is unreachable");
+            self.lint(diagnostic);
+        }
     }
 
     fn expression(&mut self) {
@@ -360,21 +818,27 @@ impl Parser {
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        let start = self.previous().span();
 
         // This will determine if the expression can be assigned to
         let can_assign = precedence <= Precedence::Assignment;
 
-        let prefix_rule = parse_rule(&self.previous_token_type()).prefix;
-        prefix_rule.expect("Expected expression")(self, can_assign);
+        let Some(prefix_rule) = parse_rule(&self.previous_token_type()).prefix else {
+            self.error("Expect expression.");
+            return;
+        };
+        prefix_rule(self, can_assign);
 
         while precedence <= parse_rule(&self.current_type()).precedence {
             self.advance();
+            // Infix rules read it before compiling their right operand, which overwrites it
+            self.expression_start = start;
             let infix_rule = parse_rule(&self.previous_token_type()).infix;
-            infix_rule.expect("Expect expression")(self);
+            infix_rule.expect("Expect expression")(self, can_assign);
         }
 
         if can_assign && self.tmatch(TokenType::Equal) {
-            self.error_at_current("Invalid assignment target");
+            self.error("Invalid assignment target");
         }
     }
 
@@ -389,53 +853,88 @@ impl Parser {
     }
 
     fn string(&mut self, _can_assign: bool) {
-        let tok = self.previous().clone();
-        self.emit_constant(Constant::String(tok.lexeme.clone()));
+        let lexeme = self.previous().lexeme.clone();
+        let string = self.interner.intern(&lexeme);
+        self.emit_constant(Constant::String(string));
     }
 
     fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.previous_token_type();
+        let start = self.previous().span();
 
         // Allows to parse nested unary expressions like !!variable
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Bang => self.emit_opcode(Opcode::Not),
-            TokenType::Minus => self.emit_opcode(Opcode::Negate),
+        let span = start.to(self.previous().span());
+        self.emit_spanning(span, |parser| match operator_type {
+            TokenType::Bang => parser.emit_opcode(Opcode::Not),
+            TokenType::Minus => parser.emit_opcode(Opcode::Negate),
             _ => unreachable!(),
-        }
+        });
     }
 
-    fn binary(&mut self) {
+    fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.previous_token_type();
+        let start = self.expression_start;
 
         let rule = parse_rule(&operator_type);
         let precedence_to_parse = (rule.precedence as u8) + 1;
         let precedence: Option<Precedence> = num::FromPrimitive::from_u8(precedence_to_parse);
         self.parse_precedence(precedence.expect("Could not convert u8 to Precedence"));
 
-        match operator_type {
-            TokenType::Plus => self.emit_opcode(Opcode::Add),
-            TokenType::Minus => self.emit_opcode(Opcode::Subtract),
-            TokenType::Star => self.emit_opcode(Opcode::Multiply),
-            TokenType::Slash => self.emit_opcode(Opcode::Divide),
+        let span = start.to(self.previous().span());
+        self.emit_spanning(span, |parser| match operator_type {
+            TokenType::Plus => parser.emit_opcode(Opcode::Add),
+            TokenType::Minus => parser.emit_opcode(Opcode::Subtract),
+            TokenType::Star => parser.emit_opcode(Opcode::Multiply),
+            TokenType::Slash => parser.emit_opcode(Opcode::Divide),
             TokenType::BangEqual => {
-                self.emit_opcode(Opcode::Equal);
-                self.emit_opcode(Opcode::Not);
+                parser.emit_opcode(Opcode::Equal);
+                parser.emit_opcode(Opcode::Not);
             },
-            TokenType::EqualEqual => self.emit_opcode(Opcode::Equal),
-            TokenType::Greater => self.emit_opcode(Opcode::Greater),
+            TokenType::EqualEqual => parser.emit_opcode(Opcode::Equal),
+            TokenType::Greater => parser.emit_opcode(Opcode::Greater),
             TokenType::GreaterEqual => {
-                self.emit_opcode(Opcode::Less);
-                self.emit_opcode(Opcode::Not);
+                parser.emit_opcode(Opcode::Less);
+                parser.emit_opcode(Opcode::Not);
             },
-            TokenType::Less => self.emit_opcode(Opcode::Less),
+            TokenType::Less => parser.emit_opcode(Opcode::Less),
             TokenType::LessEqual => {
-                self.emit_opcode(Opcode::Greater);
-                self.emit_opcode(Opcode::Not);
+                parser.emit_opcode(Opcode::Greater);
+                parser.emit_opcode(Opcode::Not);
             },
             _ => unreachable!(),
+        });
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let start = self.expression_start;
+        let arg_count = self.argument_list();
+        let span = start.to(self.previous().span());
+        self.emit_spanning(span, |parser| {
+            parser.emit_opcode(Opcode::Call);
+            parser.emit_byte(arg_count);
+        });
+    }
+
+    // Compiles the arguments of a call, leaving them on the stack, and returns how many there are
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.current_type_is(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+                if !self.tmatch(TokenType::Comma) {
+                    break;
+                }
+            }
         }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
     }
 
     fn literal(&mut self, _can_assign: bool) {
@@ -448,23 +947,78 @@ impl Parser {
     }
 
     fn emit_constant(&mut self, constant: Constant) {
-        self.chunk.write_constant(constant, self.previous().line);
+        let index = self.make_constant(constant);
+        self.emit_indexed(Opcode::Constant, index);
     }
 
+    // `return;` returns nil, except in initializers where it returns the instance
     fn emit_return(&mut self) {
+        if self.compiler().function_type == FunctionType::Initializer {
+            self.emit_opcode(Opcode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_opcode(Opcode::Nil);
+        }
         self.emit_opcode(Opcode::Return);
     }
 
+    // Code comes from the previous token, unless it's emitted for a whole expression
+    fn emit_span(&self) -> Span {
+        self.expression_span.unwrap_or_else(|| self.previous().span())
+    }
+
+    /// Emits code for an expression, so runtime errors in it point at the whole expression
+    fn emit_spanning(&mut self, span: Span, emit: impl FnOnce(&mut Parser)) {
+        self.expression_span = Some(span);
+        emit(self);
+        self.expression_span = None;
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write_byte(byte, self.previous().line);
+        let line = self.previous().line;
+        let span = self.emit_span();
+        self.current_chunk().set_span(span);
+        self.current_chunk().write_byte(byte, line);
     }
 
     fn emit_opcode(&mut self, opcode: Opcode) {
-        self.chunk.write_opcode(opcode, self.previous().line);
+        let line = self.previous().line;
+        let span = self.emit_span();
+        self.current_chunk().set_span(span);
+        self.current_chunk().write_opcode(opcode, line);
+    }
+
+    /// Emits an instruction followed by its index operand, in its long form if it has one and needs it
+    fn emit_indexed(&mut self, opcode: Opcode, index: usize) {
+        if index > u8::MAX as usize && opcode.long_form().is_none() {
+            // Not a full constant pool, the operand of this instruction is a single byte
+            self.error(&format!("Index {index} doesn't fit in the 8-bit operand of {opcode}."));
+            return;
+        }
+        let line = self.previous().line;
+        let span = self.emit_span();
+        self.current_chunk().set_span(span);
+        self.current_chunk().write_indexed(opcode, index, line);
     }
 
     fn make_constant(&mut self, constant: Constant) -> usize {
-        self.chunk.add_constant(constant)
+        let index = self.current_chunk().add_constant(constant);
+        self.check_constant_index(index)
+    }
+
+    /// Constant holding the name of a global, property or method, shared by every use of the name
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let name = self.interner.intern(name);
+        let index = self.current_chunk().write_identifier_constant(name);
+        self.check_constant_index(index)
+    }
+
+    fn check_constant_index(&mut self, index: usize) -> usize {
+        if index >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        index
     }
 
     fn previous(&self) -> &Token {
@@ -505,28 +1059,53 @@ impl Parser {
         self.previous().token_type.clone()
     }
 
+    // Error at the token just consumed
+    fn error(&mut self, message: &str) {
+        let span = self.previous().span();
+        self.report(Diagnostic::error(message, span));
+    }
+
     fn error_at_current(&mut self, message: &str) {
-        if self.panic_mode {
+        let span = self.current.as_ref().unwrap().span();
+        self.report(Diagnostic::error(message, span));
+    }
+
+    // Warnings don't affect parsing, unlike errors there is no need to recover from them
+    fn lint(&mut self, diagnostic: Diagnostic) {
+        let lint = diagnostic.lint.expect("Expected a diagnostic from a lint");
+        let severity = match self.lints.get(&lint).copied().unwrap_or_default() {
+            LintLevel::Allow => return,
+            LintLevel::Warn => Severity::Warning,
+            LintLevel::Deny => {
+                self.had_error = true;
+                Severity::Error
+            },
+        };
+        self.diagnostics.push(Diagnostic { severity, ..diagnostic });
+    }
+
+    // Local variables whose name starts with an underscore are meant to be unused
+    fn check_local_is_read(&mut self, local: &Local) {
+        if local.is_read || local.name.is_synthetic() || local.name.lexeme.starts_with('_') {
             return;
         }
-        self.panic_mode = true;
-        let token = self.current.as_ref().unwrap();
-        self.error_at(token, message);
+        let message = format!("Local variable '{}' is never read", local.name.lexeme);
+        let help = format!("if this is intentional, prefix the name with an underscore: '_{}'", local.name.lexeme);
+        self.lint(Diagnostic::warning(Lint::UnusedLocal, &message, local.name.span()).with_help(&help));
     }
 
-    fn error_at(&self, token: &Token, message: &str) {
+    // Errors found while recovering from a previous one are most likely caused by it, they are dropped
+    fn report(&mut self, diagnostic: Diagnostic) {
         if self.panic_mode {
             return;
         }
-        eprintln!("[line {0}] Error {1}", token.line, message);
-
-        match token.token_type {
-            TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at {0}",  &token.lexeme),
-        }
+        self.panic_mode = true;
+        self.had_error = true;
+        self.diagnostics.push(diagnostic);
     }
 
+    // Skips tokens until the end of the statement with the error, so the next declaration
+    // is compiled normally and its own errors are reported
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -537,59 +1116,84 @@ impl Parser {
                 | TokenType::While | TokenType::Print | TokenType::Return => { return; },
                 _ => {},
             }
+            self.advance();
         }
-        self.advance();
     }
 
     // ============= COMPILER STUFF ======
-    // TODO move to a separate file or struct
+    fn compiler(&self) -> &Compiler {
+        self.compilers.last().expect("Expected a function being compiled")
+    }
+
+    fn compiler_mut(&mut self) -> &mut Compiler {
+        self.compilers.last_mut().expect("Expected a function being compiled")
+    }
+
+    // The chunk of the innermost function being compiled, where all code is emitted
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
+
     fn is_in_global_scope(&self) -> bool {
-        self.scope_depth == 0
+        self.compiler().scope_depth == 0
     }
 
     fn is_in_local_scope(&self) -> bool {
-        self.scope_depth > 0
+        self.compiler().scope_depth > 0
     }
 
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.compiler_mut().scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
+        self.compiler_mut().scope_depth -= 1;
 
-        while !self.locals.is_empty() && self.locals.last().unwrap().depth > self.scope_depth {
-            self.emit_opcode(Opcode::Pop);
-            self.locals.pop();
+        let scope_depth = self.compiler().scope_depth;
+        while !self.compiler().locals.is_empty() && self.compiler().locals.last().unwrap().depth > scope_depth {
+            if self.compiler().locals.last().unwrap().is_captured {
+                // A closure still references this variable, move it to the heap
+                self.emit_opcode(Opcode::CloseUpvalue);
+            } else {
+                self.emit_opcode(Opcode::Pop);
+            }
+            let local = self.compiler_mut().locals.pop().unwrap();
+            self.check_local_is_read(&local);
         }
     }
 
     fn add_local(&mut self, name: Token) {
-        if self.locals.len() == u8::MAX as usize {
-            panic!("Too many local variables in function");
+        if self.compiler().locals.len() == u8::MAX as usize {
+            self.error("Too many local variables in function.");
+            return;
         }
-        self.locals.push(Local { name, depth: -1 });
+        self.compiler_mut().locals.push(Local { name, depth: -1, is_captured: false, is_read: false });
     }
 
-    fn name_already_exists_in_scope(&self, name: &Token) -> bool {
+    // The name of a local with the same name declared in the current scope
+    fn local_in_current_scope(&self, name: &Token) -> Option<Token> {
         // We iterate backwards since the current scope is going to be at the end
-        for local in self.locals.iter().rev() {
-            if local.depth != -1 && local.depth < self.scope_depth {
+        let compiler = self.compiler();
+        for local in compiler.locals.iter().rev() {
+            if local.depth != -1 && local.depth < compiler.scope_depth {
                 break;
             }
             if name.lexeme == local.name.lexeme {
-                return true;
+                return Some(local.name.clone());
             }
         }
-        false
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use common::{Constant, opcode::Opcode};
+    use std::collections::HashMap;
 
-    use crate::compiler::compile;
+    use common::{Constant, disassembler::disassemble, opcode::Opcode, span::Span};
+
+    use crate::compiler::{compile, compile_with, CompileOptions};
+    use crate::diagnostic::{Diagnostic, Lint, LintLevel, Severity};
 
     // Translates between a vector of Opcode and the u8 representation
     macro_rules! opcodes {
@@ -598,22 +1202,58 @@ mod tests {
         };
     }
 
+    #[test]
+    fn print_code_to_writer() {
+        let mut listing = Vec::new();
+        let options = CompileOptions { print_code: Some(&mut listing), ..CompileOptions::default() };
+        compile_with("fun f() {}\nreturn 4;", options).unwrap();
+        assert_eq!(String::from_utf8(listing).unwrap(), "\
+== code ==
+.const \"f\"
+.function f 0 0
+== f ==
+0000    1 NIL
+0001    | RETURN
+.end
+.const 4
+0000    1 CLOSURE             1 <fn f>
+0002    | DEFINE_GLOBAL       0 \"f\"
+0004    2 CONSTANT            2 4
+0006    | RETURN
+");
+    }
+
+    #[test]
+    fn instructions_carry_the_span_of_their_expression() {
+        let source = "var x;\nprint -x + f(1, 2);";
+        let chunk = compile(source).unwrap();
+        let span_text = |opcode: Opcode| {
+            let instructions = disassemble(&chunk);
+            let instruction = instructions.iter().find(|instruction| instruction.opcode == Some(opcode.clone())).unwrap();
+            let span = chunk.get_span(instruction.offset).unwrap();
+            (&source[span.start..span.end], span.line, span.column)
+        };
+        assert_eq!(span_text(Opcode::Negate), ("-x", 2, 7));
+        assert_eq!(span_text(Opcode::Call), ("f(1, 2)", 2, 12));
+        assert_eq!(span_text(Opcode::Add), ("-x + f(1, 2)", 2, 7));
+    }
+
     #[test]
     fn return_a_number() {
-        let Some(chunk) = compile("return 4;") else { panic!() };
+        let chunk = compile("return 4;").unwrap();
         assert_eq!(chunk.code, opcodes![Opcode::Constant, 0, Opcode::Return]);
     }
 
     #[test]
     fn return_a_string() {
-        let Some(chunk) = compile("return \"hello\";") else { panic!() };
+        let chunk = compile("return \"hello\";").unwrap();
         assert_eq!(chunk.code, opcodes![Opcode::Constant, 0, Opcode::Return]);
-        assert_eq!(chunk.constants[0], Constant::String("hello".to_string()));
+        assert_eq!(chunk.constants[0], Constant::String("hello".into()));
     }
 
     #[test]
     fn perform_math_operations() {
-        let Some(chunk) = compile("return 3 + 4 * 5;") else { panic!() };
+        let chunk = compile("return 3 + 4 * 5;").unwrap();
         assert_eq!(chunk.code, opcodes![
             Opcode::Constant, 0,
             Opcode::Constant, 1,
@@ -629,7 +1269,7 @@ mod tests {
 
     #[test]
     fn equality() {
-        let Some(chunk) = compile("return 1 == 2;") else { panic!() };
+        let chunk = compile("return 1 == 2;").unwrap();
         assert_eq!(chunk.code, opcodes![
             Opcode::Constant, 0,
             Opcode::Constant, 1,
@@ -642,77 +1282,69 @@ mod tests {
 
     #[test]
     fn global_variables() {
-        let Some(chunk) = compile("var myvar = 4;\nreturn myvar;") else { panic!() };
-        assert_eq!(chunk.constants[0], Constant::String("myvar".to_string()));
+        let chunk = compile("var myvar = 4;\nreturn myvar;").unwrap();
+        assert_eq!(chunk.constants[0], Constant::String("myvar".into()));
         assert_eq!(chunk.constants[1], Constant::Number(4.0));
+        assert_eq!(chunk.constants.len(), 2);
         assert_eq!(chunk.code, opcodes![
             Opcode::Constant, 1, // Not 0 because we have "myvar" there
             Opcode::DefineGlobal, 0,
-            Opcode::GetGlobal, 2, // Not sure why the 2 here but clox does the same
+            Opcode::GetGlobal, 0, // Every use of the name shares its constant
             Opcode::Return
         ]);
     }
 
     #[test]
     fn multiply_global_variables() {
-        let Some(chunk) = compile("var a = 3;\nvar b = 4;return a*b;") else { panic!() };
-        assert_eq!(chunk.constants[0], Constant::String("a".to_string()));
+        let chunk = compile("var a = 3;\nvar b = 4;return a*b;").unwrap();
+        assert_eq!(chunk.constants[0], Constant::String("a".into()));
         assert_eq!(chunk.constants[1], Constant::Number(3.0));
-        assert_eq!(chunk.constants[2], Constant::String("b".to_string()));
+        assert_eq!(chunk.constants[2], Constant::String("b".into()));
         assert_eq!(chunk.constants[3], Constant::Number(4.0));
         assert_eq!(chunk.code, opcodes![
             Opcode::Constant, 1, // Since in [0] we have "a"
             Opcode::DefineGlobal, 0, // Define global value for "a"
             Opcode::Constant, 3, // Since in [1] we have 3.0 and in [2] we have "b"
             Opcode::DefineGlobal, 2, // Define global value for "b"
-            Opcode::GetGlobal, 4,
-            Opcode::GetGlobal, 5,
+            Opcode::GetGlobal, 0,
+            Opcode::GetGlobal, 2,
             Opcode::Multiply,
             Opcode::Return
         ]);
     }
 
-    // TODO: this is the same as in clox but I have to figure out better why the indexes are like this
     #[test]
     fn set_global_variable() {
-        let Some(chunk) = compile("var a = 3;\na = 4;\nreturn a;") else { panic!() };
-        assert_eq!(chunk.constants[0], Constant::String("a".to_string()));
+        let chunk = compile("var a = 3;\na = 4;\nreturn a;").unwrap();
+        assert_eq!(chunk.constants[0], Constant::String("a".into()));
         assert_eq!(chunk.constants[1], Constant::Number(3.0));
-        assert_eq!(chunk.constants[2], Constant::String("a".to_string()));
-        assert_eq!(chunk.constants[3], Constant::Number(4.0));
+        assert_eq!(chunk.constants[2], Constant::Number(4.0));
         assert_eq!(chunk.code, opcodes![
             Opcode::Constant, 1,
             Opcode::DefineGlobal, 0,
-            Opcode::Constant, 3,
-            Opcode::SetGlobal, 2,
+            Opcode::Constant, 2,
+            Opcode::SetGlobal, 0,
             Opcode::Pop,
-            Opcode::GetGlobal, 4,
+            Opcode::GetGlobal, 0,
             Opcode::Return
         ]);
     }
 
     #[test]
     fn local_variables() {
-        let Some(chunk) = compile("{ var a = 4.0; print a; }") else { panic!() };
-        /*assert_eq!(chunk.constants[0], Constant::String("a".to_string()));
-        assert_eq!(chunk.constants[1], Constant::Number(3.0));
-        assert_eq!(chunk.constants[2], Constant::String("b".to_string()));
-        assert_eq!(chunk.constants[3], Constant::Number(4.0));
+        let chunk = compile("{ var a = 4.0; print a; }").unwrap();
+        assert_eq!(chunk.constants[0], Constant::Number(4.0));
         assert_eq!(chunk.code, opcodes![
-            Opcode::Constant, 1, // Since in [0] we have "a"
-            Opcode::DefineGlobal, 0, // Define global value for "a"
-            Opcode::Constant, 3, // Since in [1] we have 3.0 and in [2] we have "b"
-            Opcode::DefineGlobal, 2, // Define global value for "b"
-            Opcode::GetGlobal, 4,
-            Opcode::GetGlobal, 5,
-            Opcode::Multiply,
-            Opcode::Return
-        ]);*/
+            Opcode::Constant, 0,
+            Opcode::GetLocal, 0, // Locals live in the stack, no constant for the name
+            Opcode::Print,
+            Opcode::Pop // End of scope
+        ]);
     }
 
     #[test]
     fn test_if_statement() {
-        let Some(chunk) = compile("if (true) { print 1; } else { print 2; }") else { panic!() };
+        let chunk = compile("if (true) { print 1; } else { print 2; }").unwrap();
         assert_eq!(chunk.code, opcodes![
             Opcode::True,
             Opcode::JumpIfFalse, 0, 7, // Placeholder jump address
@@ -725,4 +1357,260 @@ mod tests {
             Opcode::Print
         ]);
     }
+
+    #[test]
+    fn function_declaration() {
+        let chunk = compile("fun add(a, b) { return a + b; }\nreturn add(1, 2);").unwrap();
+        let Constant::Function(function) = &chunk.constants[1] else { panic!() };
+        assert_eq!(function.name, "add");
+        assert_eq!(function.arity, 2);
+        assert_eq!(function.chunk.code, opcodes![
+            Opcode::GetLocal, 1, // Slot 0 is the function itself
+            Opcode::GetLocal, 2,
+            Opcode::Add,
+            Opcode::Return,
+            Opcode::Nil, // Implicit return at the end of the body
+            Opcode::Return
+        ]);
+        assert_eq!(chunk.code, opcodes![
+            Opcode::Closure, 1,
+            Opcode::DefineGlobal, 0,
+            Opcode::GetGlobal, 0,
+            Opcode::Constant, 2,
+            Opcode::Constant, 3,
+            Opcode::Call, 2,
+            Opcode::Return
+        ]);
+    }
+
+    #[test]
+    fn closure_captures_local() {
+        let chunk = compile("{ var a = 1; fun f() { return a; } }").unwrap();
+        let Constant::Function(function) = &chunk.constants[1] else { panic!() };
+        assert_eq!(function.upvalue_count, 1);
+        assert_eq!(function.chunk.code, opcodes![
+            Opcode::GetUpvalue, 0,
+            Opcode::Return,
+            Opcode::Nil,
+            Opcode::Return
+        ]);
+        assert_eq!(chunk.code, opcodes![
+            Opcode::Constant, 0,
+            Opcode::Closure, 1, 1, 0, // Captures local slot 0
+            Opcode::Pop, // f
+            Opcode::CloseUpvalue // a is captured, move it to the heap
+        ]);
+    }
+
+    #[test]
+    fn class_with_method() {
+        let chunk = compile("class A { m() { return this; } }").unwrap();
+        assert_eq!(chunk.constants[0], Constant::String("A".into()));
+        assert_eq!(chunk.constants[1], Constant::String("m".into()));
+        let Constant::Function(method) = &chunk.constants[2] else { panic!() };
+        assert_eq!(method.chunk.code, opcodes![
+            Opcode::GetLocal, 0, // this
+            Opcode::Return,
+            Opcode::Nil,
+            Opcode::Return
+        ]);
+        assert_eq!(chunk.code, opcodes![
+            Opcode::Class, 0,
+            Opcode::DefineGlobal, 0,
+            Opcode::GetGlobal, 0,
+            Opcode::Closure, 2,
+            Opcode::Method, 1,
+            Opcode::Pop
+        ]);
+    }
+
+    #[test]
+    fn this_outside_class_is_an_error() {
+        assert!(compile("return this;").is_err());
+    }
+
+    #[test]
+    fn return_value_from_initializer_is_an_error() {
+        assert!(compile("class A { init() { return 1; } }").is_err());
+    }
+
+    #[test]
+    fn class_inheriting_from_itself_is_an_error() {
+        assert!(compile("class A < A {}").is_err());
+    }
+
+    #[test]
+    fn super_outside_class_is_an_error() {
+        assert!(compile("fun f() { return super.m(); }").is_err());
+    }
+
+    #[test]
+    fn super_without_superclass_is_an_error() {
+        assert!(compile("class A { m() { return super.m(); } }").is_err());
+    }
+
+    #[test]
+    fn test_while_statement() {
+        let chunk = compile("while (false) { print 1; }").unwrap();
+        assert_eq!(chunk.code, opcodes![
+            Opcode::False,
+            Opcode::JumpIfFalse, 0, 7,
+            Opcode::Pop,
+            Opcode::Constant, 0,
+            Opcode::Print,
+            Opcode::Loop, 0, 11, // Back to the condition at 0
+            Opcode::Pop
+        ]);
+    }
+
+    #[test]
+    fn constant_long_past_255_constants() {
+        let sum = (0..300).map(|n| n.to_string()).collect::<Vec<_>>().join(" + ");
+        let chunk = compile(&format!("return {sum};")).unwrap();
+        assert_eq!(chunk.constants.len(), 300);
+        assert_eq!(chunk.constants[256], Constant::Number(256.0));
+        // `Constant 0`, then `Constant n, Add` for every other number.
+        // Constant 255 still fits in a byte, 256 needs the long form
+        let start = 2 + 254 * 3;
+        assert_eq!(chunk.code[start..start + 7], opcodes![
+            Opcode::Constant, 255,
+            Opcode::Add,
+            Opcode::ConstantLong, 0, 1, 0
+        ]);
+    }
+
+    #[test]
+    fn loop_body_too_large_is_an_error() {
+        let body = "t = t + 1;".repeat(10000);
+        let errors = compile(&format!("var t = 0;\nwhile (true) {{ {body} }}")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Loop body too large");
+    }
+
+    #[test]
+    fn too_many_locals_is_an_error() {
+        let locals = (0..256).map(|n| format!("var a{n} = {n};")).collect::<String>();
+        let errors = compile(&format!("{{ {locals} }}")).unwrap_err();
+        assert_eq!(errors[0].message, "Too many local variables in function.");
+        assert_eq!(errors[0].span.column, 3 + locals.find("var a255").unwrap() + 4);
+    }
+
+    #[test]
+    fn repeated_constants_share_an_entry() {
+        let chunk = compile("var s = \"a\";\nreturn 1 + 1 + s + \"a\";").unwrap();
+        assert_eq!(chunk.constants, vec![
+            Constant::String("s".into()),
+            Constant::String("a".into()),
+            Constant::Number(1.0),
+        ]);
+        assert_eq!(chunk.code, opcodes![
+            Opcode::Constant, 1,
+            Opcode::DefineGlobal, 0,
+            Opcode::Constant, 2,
+            Opcode::Constant, 2,
+            Opcode::Add,
+            Opcode::GetGlobal, 0,
+            Opcode::Add,
+            Opcode::Constant, 1,
+            Opcode::Add,
+            Opcode::Return
+        ]);
+    }
+
+    #[test]
+    fn errors_are_returned_as_diagnostics() {
+        let errors = compile("{ var a = 1; var a = 2; }").unwrap_err();
+        assert_eq!(errors, vec![
+            Diagnostic::error("Variable with this name already declared in this scope", Span { line: 1, column: 18, start: 17, end: 18 })
+                .with_label(Span { line: 1, column: 7, start: 6, end: 7 }, "previously declared here")
+        ]);
+    }
+
+    #[test]
+    fn missing_expression_is_an_error() {
+        let errors = compile("print ;").unwrap_err();
+        assert_eq!(errors[0].message, "Expect expression.");
+        assert_eq!(errors[0].span.column, 7);
+    }
+
+    #[test]
+    fn every_independent_error_is_reported() {
+        let source = "print 1 +;\nvar = 2 3 4;\nprint (3;\nprint 4;";
+        let errors = compile(source).unwrap_err();
+        let found: Vec<_> = errors.iter().map(|error| (error.span.line, error.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (1, "Expect expression."),
+            (2, "Expected variable name"),
+            (3, "Expect ')' after expression"),
+        ]);
+    }
+
+    #[test]
+    fn recovery_resumes_after_a_block() {
+        let errors = compile("fun f() { return 1 2; }\nclass { }\nprint f();").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expect ';' after return value.");
+        assert_eq!(errors[1].message, "Expect class name.");
+    }
+
+    fn warnings(source: &str) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        compile_with(source, CompileOptions { warnings: Some(&mut warnings), ..CompileOptions::default() }).unwrap();
+        warnings
+    }
+
+    fn lints(warnings: &[Diagnostic]) -> Vec<(Option<Lint>, &str)> {
+        warnings.iter().map(|warning| (warning.lint, warning.message.as_str())).collect()
+    }
+
+    #[test]
+    fn unused_local_warning() {
+        let found = warnings("{ var a = 1; var b = 2; var _c; b = a; }\nfun f(x, y) { var z; return y; }");
+        assert_eq!(lints(&found), vec![
+            (Some(Lint::UnusedLocal), "Local variable 'b' is never read"),
+            (Some(Lint::UnusedLocal), "Local variable 'z' is never read"),
+        ]);
+        assert_eq!(found[0].span.column, 18);
+        assert_eq!(found[0].help.as_deref(), Some("if this is intentional, prefix the name with an underscore: '_b'"));
+
+        // Parameters are never reported, methods and callbacks often ignore some
+        assert!(warnings("fun f(a, b) { return a; }\nclass C { m(x) {} }").is_empty());
+
+        // Reading it from a closure counts
+        assert!(warnings("{ var a = 1; fun f() { print a; } f(); }").is_empty());
+    }
+
+    #[test]
+    fn shadowing_warning() {
+        let found = warnings("var a = 1;\n{ var b = 2; { var b = 3; print b; } print b; }\nfun f(a) { return a; }");
+        assert_eq!(lints(&found), vec![
+            (Some(Lint::Shadowing), "Variable 'b' shadows a local variable"),
+            (Some(Lint::Shadowing), "Variable 'a' shadows a global variable"),
+        ]);
+        assert_eq!(found[0].labels[0].span, Span { line: 2, column: 7, start: 17, end: 18 });
+        assert_eq!(found[1].labels[0].span, Span { line: 1, column: 5, start: 4, end: 5 });
+    }
+
+    #[test]
+    fn unreachable_code_warning() {
+        let found = warnings("fun f() {\n  return 1;\n  print 2;\n  print 3;\n}\nfun g() { if (true) return 1; return 2; }");
+        assert_eq!(lints(&found), vec![(Some(Lint::UnreachableCode), "Unreachable code")]);
+        assert_eq!(found[0].span, Span { line: 3, column: 3, start: 24, end: 43 });
+        assert_eq!(found[0].labels[0].span.line, 2);
+    }
+
+    #[test]
+    fn lints_can_be_allowed_or_denied() {
+        let source = "{ var a; }";
+        let allow = HashMap::from([(Lint::UnusedLocal, LintLevel::Allow)]);
+        let mut warnings = Vec::new();
+        compile_with(source, CompileOptions { warnings: Some(&mut warnings), lints: allow, ..CompileOptions::default() }).unwrap();
+        assert!(warnings.is_empty());
+
+        let deny = HashMap::from([(Lint::UnusedLocal, LintLevel::Deny)]);
+        let errors = compile_with(source, CompileOptions { lints: deny, ..CompileOptions::default() }).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert_eq!(errors[0].lint, Some(Lint::UnusedLocal));
+    }
 }
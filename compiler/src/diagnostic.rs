@@ -0,0 +1,247 @@
+//! Errors found while compiling, and how to show them next to the source code.
+
+use std::fmt;
+
+use common::span::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Checks for code that compiles but is likely a mistake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A local variable that is never read, parameters aside
+    UnusedLocal,
+    /// A local variable with the same name as a local of an outer scope or a global
+    Shadowing,
+    /// Statements after a `return` in the same block
+    UnreachableCode,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 3] = [Lint::UnusedLocal, Lint::Shadowing, Lint::UnreachableCode];
+
+    /// Name used to allow or deny the lint, it never changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::UnusedLocal => "unused-local",
+            Lint::Shadowing => "shadowing",
+            Lint::UnreachableCode => "unreachable-code",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.code() == code)
+    }
+}
+
+/// What to do when a lint finds something
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LintLevel {
+    Allow,
+    #[default]
+    Warn,
+    /// Report it as an error, the compilation fails
+    Deny,
+}
+
+/// Points at some other code related to the diagnostic, e.g. a previous declaration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The lint that found it, None for errors that always fail the compilation
+    pub lint: Option<Lint>,
+    pub message: String,
+    /// The code the diagnostic is about
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            lint: None,
+            message: message.to_string(),
+            span,
+            labels: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn warning(lint: Lint, message: &str, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            lint: Some(lint),
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, message: &str) -> Diagnostic {
+        self.labels.push(Label { span, message: message.to_string() });
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Diagnostic {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// The diagnostic with the lines of `source` it points at, underlined
+    pub fn render(&self, source: &str) -> String {
+        let mut out = String::new();
+        write_diagnostic(&mut out, source, self).expect("Writing to a String can't fail");
+        out
+    }
+
+    // The severity, followed by the lint code if there is one: warning[unused-local]
+    fn heading(&self) -> String {
+        match self.lint {
+            Some(lint) => format!("{}[{}]", self.severity, lint.code()),
+            None => self.severity.to_string(),
+        }
+    }
+}
+
+/// One line summary, without the source code
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}, column {}] {}: {}", self.span.line, self.span.column, self.heading(), self.message)
+    }
+}
+
+/// Writes the diagnostic followed by the source lines it points at, e.g.
+///
+/// ```text
+/// error: Variable with this name already declared in this scope
+///  --> line 1, column 18
+///   |
+/// 1 | { var a = 1; var a = 2; }
+///   |                  ^
+///   |       - previously declared here
+/// ```
+pub fn write_diagnostic(out: &mut impl fmt::Write, source: &str, diagnostic: &Diagnostic) -> fmt::Result {
+    writeln!(out, "{}: {}", diagnostic.heading(), diagnostic.message)?;
+
+    // The primary span is underlined with carets, labels with dashes
+    let mut marks = vec![(diagnostic.span, '^', "")];
+    marks.extend(diagnostic.labels.iter().map(|label| (label.span, '-', label.message.as_str())));
+    let mut lines: Vec<usize> = marks.iter().map(|(span, _, _)| span.line).collect();
+    lines.sort_unstable();
+    lines.dedup();
+
+    let gutter = " ".repeat(lines.last().map_or(1, |line| line.to_string().len()));
+    writeln!(out, "{gutter}--> line {}, column {}", diagnostic.span.line, diagnostic.span.column)?;
+    writeln!(out, "{gutter} |")?;
+
+    let mut previous_line = None;
+    for line in lines {
+        if previous_line.is_some_and(|previous| previous + 1 < line) {
+            writeln!(out, "...")?;
+        }
+        previous_line = Some(line);
+
+        let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+        writeln!(out, "{line:>width$} | {text}", width = gutter.len())?;
+        for (span, marker, message) in marks.iter().filter(|(span, _, _)| span.line == line) {
+            let padding = indentation(text, span.column);
+            let underline = marker.to_string().repeat(underline_width(source, span));
+            let row = format!("{padding}{underline} {message}");
+            writeln!(out, "{gutter} | {}", row.trim_end())?;
+        }
+    }
+
+    if let Some(help) = &diagnostic.help {
+        writeln!(out, "{gutter} = help: {help}")?;
+    }
+    Ok(())
+}
+
+// Blank space up to the column, keeping tabs so the marks line up with the text above them
+fn indentation(text: &str, column: usize) -> String {
+    let before = text.chars().take(column.saturating_sub(1));
+    before.map(|c| if c == '\t' { '\t' } else { ' ' }).collect()
+}
+
+// Characters spanned on the first line, at least one so empty spans (like the end of the file) show up
+fn underline_width(source: &str, span: &Span) -> usize {
+    let text = source.get(span.start..span.end).unwrap_or("");
+    let first_line = text.split('\n').next().unwrap_or("");
+    first_line.chars().count().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_underlines_the_span() {
+        let source = "var a = 1;\nprint a +;\n";
+        let diagnostic = Diagnostic::error("Expect expression.", Span { line: 2, column: 10, start: 20, end: 21 });
+        assert_eq!(diagnostic.render(source), "\
+error: Expect expression.
+ --> line 2, column 10
+  |
+2 | print a +;
+  |          ^
+");
+    }
+
+    #[test]
+    fn test_render_labels_and_help() {
+        let source = "{\n  var long = 1;\n\n\n\tvar long = 2;\n}";
+        let diagnostic = Diagnostic::error("Already declared", Span { line: 5, column: 6, start: 25, end: 29 })
+            .with_label(Span { line: 2, column: 7, start: 8, end: 12 }, "first declared here")
+            .with_help("use another name");
+        assert_eq!(diagnostic.render(source), "\
+error: Already declared
+ --> line 5, column 6
+  |
+2 |   var long = 1;
+  |       ---- first declared here
+...
+5 | \tvar long = 2;
+  | \t    ^^^^
+  = help: use another name
+");
+    }
+
+    #[test]
+    fn test_render_end_of_file() {
+        let source = "print 1";
+        let diagnostic = Diagnostic::error("Expect ';' after value.", Span { line: 1, column: 8, start: 7, end: 7 });
+        assert!(diagnostic.render(source).ends_with("1 | print 1\n  |        ^\n"));
+        assert_eq!(diagnostic.to_string(), "[line 1, column 8] error: Expect ';' after value.");
+    }
+
+    #[test]
+    fn test_lint_codes() {
+        for lint in Lint::ALL {
+            assert_eq!(Lint::from_code(lint.code()), Some(lint));
+        }
+        assert_eq!(Lint::from_code("unused"), None);
+
+        let diagnostic = Diagnostic::warning(Lint::UnusedLocal, "Local variable 'a' is never read", Span { line: 1, column: 7, start: 6, end: 7 });
+        assert_eq!(diagnostic.to_string(), "[line 1, column 7] warning[unused-local]: Local variable 'a' is never read");
+        assert!(diagnostic.render("{ var a; }").starts_with("warning[unused-local]: Local variable 'a' is never read\n"));
+    }
+}
@@ -1,8 +1,16 @@
 use common::chunk::Chunk;
 
 pub mod compiler;
+pub mod diagnostic;
 pub mod scanner;
 
-pub fn compile(code: &str) -> Option<Chunk> {
+pub use compiler::CompileOptions;
+pub use diagnostic::{Diagnostic, Severity};
+
+pub fn compile(code: &str) -> Result<Chunk, Vec<Diagnostic>> {
     compiler::compile(code)
 }
+
+pub fn compile_with(code: &str, options: CompileOptions) -> Result<Chunk, Vec<Diagnostic>> {
+    compiler::compile_with(code, options)
+}
@@ -1,6 +1,9 @@
 use phf_macros::phf_map;
 
+use common::span::Span;
+
 #[derive(Eq, PartialEq, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum TokenType {
     // Single-character tokens.
     LeftParen, RightParen, LeftBrace, RightBrace, Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
@@ -38,17 +41,47 @@ static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
 #[allow(dead_code)]
 pub struct Token {
     pub(crate) token_type: TokenType,
+    /// Byte offset in the source
     pub(crate) start: usize,
+    /// Length in bytes
     pub(crate) length: usize,
+    /// Line and column where the token starts
     pub(crate) line: usize,
+    pub(crate) column: usize,
     pub(crate) lexeme: String,
 }
 
+impl Token {
+    /// A token that doesn't come from the source code
+    pub(crate) fn synthetic(lexeme: &str) -> Token {
+        Token {
+            token_type: TokenType::Identifier,
+            start: 0,
+            length: lexeme.len(),
+            line: 0,
+            column: 0,
+            lexeme: lexeme.to_string(),
+        }
+    }
+
+    pub(crate) fn is_synthetic(&self) -> bool {
+        self.line == 0
+    }
+
+    pub(crate) fn span(&self) -> Span {
+        Span { line: self.line, column: self.column, start: self.start, end: self.start + self.length }
+    }
+}
+
 pub struct Scanner {
     source: String,
     start: usize,
     current: usize,
     line: usize,
+    column: usize,
+    // Where the token being scanned starts
+    start_line: usize,
+    start_column: usize,
 }
 
 pub fn init_scanner(source: &str) -> Scanner {
@@ -57,6 +90,9 @@ pub fn init_scanner(source: &str) -> Scanner {
         start: 0,
         current: 0,
         line: 1,
+        column: 1,
+        start_line: 1,
+        start_column: 1,
     }
 }
 
@@ -64,6 +100,8 @@ impl Scanner {
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
         }
@@ -74,7 +112,7 @@ impl Scanner {
             return self.identifier();
         }
 
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number();
         }
 
@@ -133,10 +171,10 @@ impl Scanner {
         if self.is_at_end() {
             return false;
         }
-        if self.char_at(self.current) != expected {
+        if self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
         true
     }
 
@@ -145,18 +183,12 @@ impl Scanner {
             let c = self.peek();
 
             match c {
-                ' ' | '\r' | '\t' => self.current += 1,
-                '\n' => {
-                    self.line += 1;
-                    self.current += 1;
+                ' ' | '\r' | '\t' | '\n' => {
+                    self.advance();
                 },
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.current += 1;
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 },
                 _ => return,
@@ -166,7 +198,7 @@ impl Scanner {
 
     fn identifier(&mut self) -> Token {
         while self.peek().is_alphanumeric() || self.peek() == '_' {
-            self.current += 1;
+            self.advance();
         }
 
         self.make_token(self.identifier_type())
@@ -175,21 +207,21 @@ impl Scanner {
     fn identifier_type(&self) -> TokenType {
         let text = &self.source[self.start..self.current];
         match KEYWORDS.get(text) {
-            Some(&ref token_type) => token_type.clone(),
+            Some(token_type) => token_type.clone(),
             None => TokenType::Identifier,
         }
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
-            self.current += 1;
+        while self.peek().is_ascii_digit() {
+            self.advance();
         }
 
         // Look for a fractional part
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
-            self.current += 1;
-            while self.peek().is_digit(10) {
-                self.current += 1;
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
+            self.advance();
+            while self.peek().is_ascii_digit() {
+                self.advance();
             }
         }
 
@@ -198,28 +230,20 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
-            self.current += 1;
+            self.advance();
         }
 
         if self.is_at_end() {
             return self.error_token("Unterminated string");
         }
 
-        // Remove quotes from string
-        self.start += 1;
-        self.current -= 1;
-
+        // The closing quote
         self.advance();
-        let tok = self.make_token(TokenType::String);
 
-        // Restore start and current to continue scanning correctly
-        self.start -= 1;
-        self.current += 1;
-
-        tok
+        // Remove quotes from the lexeme, they are a single byte each. The token still spans them
+        let mut token = self.make_token(TokenType::String);
+        token.lexeme = self.source[self.start + 1..self.current - 1].to_string();
+        token
     }
 
     fn make_token(&self, token_type: TokenType) -> Token {
@@ -227,44 +251,43 @@ impl Scanner {
             token_type,
             start: self.start,
             length: (self.current - self.start),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             lexeme: self.source[self.start..self.current].to_string(),
         }
     }
 
+    // Spans the text that caused the error, the lexeme is the message
     fn error_token(&self, message: &str) -> Token {
         Token {
             token_type: TokenType::Error,
-            start: 0,
-            length: message.len(),
-            line: self.line,
+            start: self.start,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
             lexeme: message.to_string(),
         }
     }
 
+    // Offsets are in bytes, so moving past a character moves past all of its UTF-8 bytes
     fn advance(&mut self) -> char {
-        let current_char = self.char_at(self.current);
-        self.current += 1;
+        let current_char = self.peek();
+        self.current += current_char.len_utf8();
+        if current_char == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         current_char
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.char_at(self.current)
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.char_at(self.current + 1)
-    }
-
-    fn char_at(&self, index: usize) -> char {
-        self.source.chars().nth(index).unwrap()
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 }
 
@@ -347,4 +370,54 @@ mod tests {
         assert_eq!(token.token_type, super::TokenType::String);
         assert_eq!(token.lexeme, "man");
     }
+
+    #[test]
+    fn multibyte_characters() {
+        let source = "\"héllo wörld 🦀\" // ünïcode comment\nnaïve";
+        let mut scanner = super::init_scanner(source);
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, super::TokenType::String);
+        assert_eq!(token.lexeme, "héllo wörld 🦀");
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, super::TokenType::Identifier);
+        assert_eq!(token.lexeme, "naïve");
+        assert_eq!(token.line, 2);
+        assert_eq!(scanner.scan_token().token_type, super::TokenType::EOF);
+    }
+
+    #[test]
+    fn unexpected_multibyte_character() {
+        let mut scanner = super::init_scanner("€ 1");
+        assert_eq!(scanner.scan_token().token_type, super::TokenType::Error);
+        assert_eq!(scanner.scan_token().lexeme, "1");
+    }
+
+    #[test]
+    fn large_source_scans_in_linear_time() {
+        // About 1 MB, quadratic scanning would take minutes
+        let source = "var s = \"ünïcode\"; // çomment\n".repeat(32 * 1024);
+        let mut scanner = super::init_scanner(&source);
+        let mut count = 0;
+        while scanner.scan_token().token_type != super::TokenType::EOF {
+            count += 1;
+        }
+        assert_eq!(count, 5 * 32 * 1024);
+    }
+
+    #[test]
+    fn spans() {
+        let source = "var é = \"a\nb\";\n  @";
+        let mut scanner = super::init_scanner(source);
+        let spans: Vec<_> = std::iter::repeat_with(|| scanner.scan_token().span()).take(6).collect();
+        assert_eq!(spans, vec![
+            super::Span { line: 1, column: 1, start: 0, end: 3 },
+            super::Span { line: 1, column: 5, start: 4, end: 6 },
+            super::Span { line: 1, column: 7, start: 7, end: 8 },
+            // Strings span their quotes and can go over several lines
+            super::Span { line: 1, column: 9, start: 9, end: 14 },
+            super::Span { line: 2, column: 3, start: 14, end: 15 },
+            // The unexpected character
+            super::Span { line: 3, column: 3, start: 18, end: 19 },
+        ]);
+    }
 }
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::bytecode::MAGIC;
+use common::cfg::write_dot;
+use common::chunk::Chunk;
+use common::verifier::verify;
+use compiler::diagnostic::{Lint, LintLevel};
+use compiler::CompileOptions;
+use vm::error::RuntimeError;
+use vm::value::Value;
+use vm::vm::{VmOptions, VM};
+
+// Exit codes follow the sysexits.h convention, same as clox
+const EXIT_USAGE: u8 = 64;
+const EXIT_COMPILE_ERROR: u8 = 65;
+const EXIT_RUNTIME_ERROR: u8 = 70;
+const EXIT_IO_ERROR: u8 = 74;
+
+/// Debugging output and lint levels selected on the command line
+#[derive(Clone, Default)]
+struct Debug {
+    print_code: bool,
+    trace_execution: bool,
+    lints: HashMap<Lint, LintLevel>,
+}
+
+impl Debug {
+    /// Compiles the source, printing every warning and error next to the code it's about
+    fn compile(&self, source: &str) -> Option<Chunk> {
+        let mut stdout = io::stdout();
+        let mut warnings = Vec::new();
+        let options = CompileOptions {
+            print_code: self.print_code.then_some(&mut stdout as &mut dyn Write),
+            warnings: Some(&mut warnings),
+            lints: self.lints.clone(),
+        };
+        let compiled = compiler::compile_with(source, options);
+        for warning in warnings {
+            eprintln!("{}", warning.render(source));
+        }
+        match compiled {
+            Ok(chunk) => Some(chunk),
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}", error.render(source));
+                }
+                None
+            }
+        }
+    }
+
+    fn vm(&self, chunk: Chunk) -> VM {
+        let options = VmOptions {
+            trace_execution: self.trace_execution.then(|| Box::new(io::stdout()) as Box<dyn Write>),
+            ..VmOptions::default()
+        };
+        let mut vm = VM::init_with_options(chunk, options);
+        define_natives(&mut vm);
+        vm
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut debug = Debug::default();
+    let mut unknown_lints = Vec::new();
+    args.retain(|arg| match arg.as_str() {
+        "--print-code" => { debug.print_code = true; false },
+        "--trace-execution" => { debug.trace_execution = true; false },
+        _ => match lint_flag(arg) {
+            Some((level, code)) => {
+                match Lint::from_code(code) {
+                    Some(lint) => { debug.lints.insert(lint, level); },
+                    None => unknown_lints.push(code.to_string()),
+                }
+                false
+            },
+            None => true,
+        },
+    });
+    if !unknown_lints.is_empty() {
+        let codes: Vec<_> = Lint::ALL.iter().map(Lint::code).collect();
+        eprintln!("Unknown lint {}, expected one of: {}", unknown_lints.join(", "), codes.join(", "));
+        return ExitCode::from(EXIT_USAGE);
+    }
+
+    // Only the lint levels apply when compiling without running
+    let lints_only = Debug { lints: debug.lints.clone(), ..Debug::default() };
+    match args.len() {
+        0 => repl(debug),
+        1 => run_file(&args[0], &debug),
+        2 if args[0] == "--cfg" => print_cfg(&args[1], &lints_only),
+        3 if args[0] == "--compile" => compile_file(&args[1], &args[2], &lints_only),
+        _ => {
+            eprintln!("Usage: rustylox [--print-code] [--trace-execution] [lints] [path]");
+            eprintln!("       rustylox [lints] --compile <script.lox> <output.loxc>");
+            eprintln!("       rustylox [lints] --cfg <script.lox>");
+            eprintln!("Lints: --allow=<lint>, --warn=<lint> or --deny=<lint>");
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+/// --allow=<lint>, --warn=<lint> and --deny=<lint>
+fn lint_flag(arg: &str) -> Option<(LintLevel, &str)> {
+    let (flag, code) = arg.split_once('=')?;
+    let level = match flag {
+        "--allow" => LintLevel::Allow,
+        "--warn" => LintLevel::Warn,
+        "--deny" => LintLevel::Deny,
+        _ => return None,
+    };
+    Some((level, code))
+}
+
+/// Run a whole file, either a .lox script or bytecode compiled with --compile
+fn run_file(path: &str, debug: &Debug) -> ExitCode {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Could not read file \"{path}\": {err}");
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    };
+
+    let chunk = if contents.starts_with(&MAGIC) {
+        let chunk = match Chunk::read_from(contents.as_slice()) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                eprintln!("Could not load \"{path}\": {err}");
+                return ExitCode::from(EXIT_COMPILE_ERROR);
+            }
+        };
+        // Bytecode files may come from anywhere, don't let a broken one crash the VM
+        if let Err(errors) = verify(&chunk) {
+            eprintln!("Could not load \"{path}\": invalid bytecode");
+            for error in errors {
+                eprintln!("  {error}");
+            }
+            return ExitCode::from(EXIT_COMPILE_ERROR);
+        }
+        chunk
+    } else {
+        let Some(chunk) = compile_source(path, &contents, debug) else {
+            return ExitCode::from(EXIT_COMPILE_ERROR);
+        };
+        chunk
+    };
+
+    let mut vm = debug.vm(chunk);
+    match vm.run() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprint!("{error}\n{}", error.stack_trace());
+            ExitCode::from(EXIT_RUNTIME_ERROR)
+        }
+    }
+}
+
+/// Compile a .lox file to bytecode that can be run later without the source
+fn compile_file(path: &str, output: &str, debug: &Debug) -> ExitCode {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Could not read file \"{path}\": {err}");
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    };
+    let Some(chunk) = compile_source(path, &contents, debug) else {
+        return ExitCode::from(EXIT_COMPILE_ERROR);
+    };
+
+    let written = std::fs::File::create(output)
+        .and_then(|file| chunk.write_to(io::BufWriter::new(file)));
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Could not write file \"{output}\": {err}");
+            ExitCode::from(EXIT_IO_ERROR)
+        }
+    }
+}
+
+/// Print the control-flow graph of a .lox file in Graphviz DOT format
+fn print_cfg(path: &str, debug: &Debug) -> ExitCode {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Could not read file \"{path}\": {err}");
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    };
+    let Some(chunk) = compile_source(path, &contents, debug) else {
+        return ExitCode::from(EXIT_COMPILE_ERROR);
+    };
+
+    let mut dot = String::new();
+    write_dot(&mut dot, &chunk, "script").expect("Writing to a String can't fail");
+    print!("{dot}");
+    ExitCode::SUCCESS
+}
+
+fn compile_source(path: &str, contents: &[u8], debug: &Debug) -> Option<Chunk> {
+    let Ok(source) = std::str::from_utf8(contents) else {
+        eprintln!("Could not read file \"{path}\": not valid UTF-8");
+        return None;
+    };
+    debug.compile(source)
+}
+
+/// Functions from the host available to every script
+fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+}
+
+/// Seconds since the Unix epoch, useful for benchmarking scripts
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(Value::Number(elapsed.as_secs_f64())),
+        Err(_) => Err(RuntimeError::new("System clock is set before the Unix epoch")),
+    }
+}
+
+/// Read-eval-print loop, every line is compiled and run on the same VM so globals persist
+fn repl(debug: Debug) -> ExitCode {
+    let mut vm = debug.vm(Chunk::init());
+    let stdin = io::stdin();
+    let mut line = String::new();
+
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("Could not flush stdout");
+
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                // EOF (Ctrl-D)
+                println!();
+                return ExitCode::SUCCESS;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Could not read line: {err}");
+                return ExitCode::from(EXIT_IO_ERROR);
+            }
+        }
+
+        if let Some(chunk) = debug.compile(&line) {
+            if let Err(error) = vm.interpret(chunk) {
+                eprint!("{error}\n{}", error.stack_trace());
+            }
+        }
+    }
+}
@@ -1,17 +1,45 @@
-use common::Value;
-#[cfg(test)]
-use compiler;
-use vm::vm::{InterpretResult, VM};
+#![cfg(test)]
+
+use common::assembler::assemble;
+use common::chunk::Chunk;
+use common::Constant;
+use common::disassembler::{disassemble, write_chunk};
+use common::opcode::Opcode;
+use common::span::Span;
+use common::verifier::verify;
+use vm::error::{RuntimeError, RuntimeErrorKind, TraceFrame};
+use vm::heap::GcConfig;
+use vm::value::Value;
+use vm::vm::VM;
 
 macro_rules! run_code {
     ($code:expr, $expected:expr) => {
         let chunk = compiler::compile($code).expect("Failed to compile");
-        let (status, Some(value)) = VM::init(chunk).run() else { panic!("failed to execute vm") };
-        assert_eq!(status, InterpretResult::OK);
+        assert_eq!(verify(&chunk), Ok(()));
+        let value = VM::init(chunk).run().expect("failed to execute vm");
         assert_eq!(value, $expected);
     };
 }
 
+macro_rules! run_code_str {
+    ($code:expr, $expected:expr) => {
+        let chunk = compiler::compile($code).expect("Failed to compile");
+        assert_eq!(verify(&chunk), Ok(()));
+        let mut vm = VM::init(chunk);
+        let value = vm.run().expect("failed to execute vm");
+        assert_eq!(vm.heap.as_string(value), Some($expected));
+    };
+}
+
+macro_rules! run_code_runtime_error {
+    ($code:expr, $kind:expr) => {
+        let chunk = compiler::compile($code).expect("Failed to compile");
+        assert_eq!(verify(&chunk), Ok(()));
+        let error = VM::init(chunk).run().expect_err("expected a runtime error");
+        assert_eq!(error.kind, $kind);
+    };
+}
+
 #[test]
 fn basic_return() {
     let code = r#"
@@ -44,4 +72,670 @@ fn test_with_if() {
         return a;
     "#;
     run_code!(code, Value::Number(3.0));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_with_for() {
+    let code = r#"
+        var sum = 0;
+        for (var i = 0; i < 5; i = i + 1) {
+            sum = sum + i;
+        }
+        return sum;
+    "#;
+    run_code!(code, Value::Number(10.0));
+}
+
+#[test]
+fn test_for_variable_is_scoped_to_the_loop() {
+    let code = r#"
+        var i = 10;
+        for (var i = 0; i < 3; i = i + 1) {}
+        return i;
+    "#;
+    run_code!(code, Value::Number(10.0));
+}
+
+#[test]
+fn test_for_without_clauses() {
+    let code = r#"
+        var i = 0;
+        for (;;) {
+            i = i + 1;
+            if (i == 4) {
+                return i;
+            }
+        }
+    "#;
+    run_code!(code, Value::Number(4.0));
+}
+
+#[test]
+fn test_for_with_expression_initializer() {
+    let code = r#"
+        var i;
+        var product = 1;
+        for (i = 1; i < 5;) {
+            product = product * i;
+            i = i + 1;
+        }
+        return product;
+    "#;
+    run_code!(code, Value::Number(24.0));
+}
+
+#[test]
+fn test_function_call() {
+    let code = r#"
+        fun add(a, b, c) {
+            return a + b + c;
+        }
+        return add(1, 2, 3);
+    "#;
+    run_code!(code, Value::Number(6.0));
+}
+
+#[test]
+fn test_recursive_function() {
+    let code = r#"
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 2) + fib(n - 1);
+        }
+        return fib(10);
+    "#;
+    run_code!(code, Value::Number(55.0));
+}
+
+#[test]
+fn test_function_without_return_returns_nil() {
+    let code = r#"
+        fun nothing() {
+            var a = 1;
+        }
+        return nothing();
+    "#;
+    run_code!(code, Value::Nil);
+}
+
+#[test]
+fn test_local_function_and_frame_relative_locals() {
+    let code = r#"
+        {
+            var x = 10;
+            fun scale(n) {
+                var factor = 3;
+                return n * factor;
+            }
+            var y = scale(x) + x;
+            return y;
+        }
+    "#;
+    run_code!(code, Value::Number(40.0));
+}
+
+#[test]
+fn test_counter_closure() {
+    let code = r#"
+        fun make_counter() {
+            var count = 0;
+            fun increment() {
+                count = count + 1;
+                return count;
+            }
+            return increment;
+        }
+        var counter = make_counter();
+        var other = make_counter();
+        counter();
+        counter();
+        other();
+        return counter();
+    "#;
+    run_code!(code, Value::Number(3.0));
+}
+
+#[test]
+fn test_closures_share_captured_variable() {
+    let code = r#"
+        var get;
+        var set;
+        fun make() {
+            var value = 1;
+            fun g() { return value; }
+            fun s(v) { value = v; }
+            get = g;
+            set = s;
+        }
+        make();
+        set(42);
+        return get();
+    "#;
+    run_code!(code, Value::Number(42.0));
+}
+
+#[test]
+fn test_closures_in_loop() {
+    let code = r#"
+        var first;
+        var second;
+        for (var i = 1; i < 3; i = i + 1) {
+            var j = i;
+            fun f() { return j * 10; }
+            if (i == 1) first = f; else second = f;
+        }
+        return first() + second();
+    "#;
+    run_code!(code, Value::Number(30.0));
+}
+
+#[test]
+fn test_nested_closure_captures_through_enclosing_function() {
+    let code = r#"
+        fun outer() {
+            var x = "outside";
+            fun middle() {
+                fun inner() {
+                    return x;
+                }
+                return inner;
+            }
+            return middle;
+        }
+        return outer()()();
+    "#;
+    run_code_str!(code, "outside");
+}
+
+#[test]
+fn test_anonymous_function_callback() {
+    let code = r#"
+        fun apply_twice(f, value) {
+            return f(f(value));
+        }
+        var offset = 5;
+        return apply_twice(fun (n) { return n + offset; }, 1);
+    "#;
+    run_code!(code, Value::Number(11.0));
+}
+
+#[test]
+fn test_class_fields() {
+    let code = r#"
+        class Point {}
+        var p = Point();
+        p.x = 3;
+        p.y = 4;
+        return p.x * p.y;
+    "#;
+    run_code!(code, Value::Number(12.0));
+}
+
+#[test]
+fn test_class_methods_and_initializer() {
+    let code = r#"
+        class Rectangle {
+            init(width, height) {
+                this.width = width;
+                this.height = height;
+            }
+            area() {
+                return this.width * this.height;
+            }
+            scale(factor) {
+                this.width = this.width * factor;
+                return this;
+            }
+        }
+        var r = Rectangle(2, 5);
+        return r.scale(3).area();
+    "#;
+    run_code!(code, Value::Number(30.0));
+}
+
+#[test]
+fn test_bound_method_remembers_receiver() {
+    let code = r#"
+        class Greeter {
+            init(name) { this.name = name; }
+            greet() { return "hi " + this.name; }
+        }
+        var greet = Greeter("bob").greet;
+        return greet();
+    "#;
+    run_code_str!(code, "hi bob");
+}
+
+#[test]
+fn test_this_captured_by_closure_in_method() {
+    let code = r#"
+        class Counter {
+            init() { this.count = 0; }
+            incrementer() {
+                fun increment() {
+                    this.count = this.count + 1;
+                    return this.count;
+                }
+                return increment;
+            }
+        }
+        var counter = Counter();
+        var increment = counter.incrementer();
+        increment();
+        increment();
+        return counter.count;
+    "#;
+    run_code!(code, Value::Number(2.0));
+}
+
+#[test]
+fn test_early_return_in_initializer_returns_instance() {
+    let code = r#"
+        class Foo {
+            init() {
+                this.a = 1;
+                return;
+            }
+        }
+        return Foo().init().a;
+    "#;
+    run_code!(code, Value::Number(1.0));
+}
+
+#[test]
+fn test_undefined_property_is_runtime_error() {
+    let code = r#"
+        class Empty {}
+        return Empty().missing;
+    "#;
+    run_code_runtime_error!(code, RuntimeErrorKind::UndefinedProperty);
+}
+
+#[test]
+fn test_field_access_on_non_instance_is_runtime_error() {
+    let code = r#"
+        var number = 4;
+        return number.field;
+    "#;
+    run_code_runtime_error!(code, RuntimeErrorKind::Type);
+}
+
+#[test]
+fn test_class_without_initializer_rejects_arguments() {
+    let code = r#"
+        class Empty {}
+        return Empty(1);
+    "#;
+    run_code_runtime_error!(code, RuntimeErrorKind::Arity);
+}
+
+#[test]
+fn test_inherited_methods() {
+    let code = r#"
+        class Shape {
+            init(name) { this.name = name; }
+            describe() { return this.name + " with area"; }
+        }
+        class Square < Shape {}
+        return Square("square").describe();
+    "#;
+    run_code_str!(code, "square with area");
+}
+
+#[test]
+fn test_override_and_super_call() {
+    let code = r#"
+        class Base {
+            init(x) { this.x = x; }
+            value() { return this.x; }
+        }
+        class Derived < Base {
+            init(x, y) {
+                super.init(x);
+                this.y = y;
+            }
+            value() { return super.value() * 10 + this.y; }
+        }
+        return Derived(4, 2).value();
+    "#;
+    run_code!(code, Value::Number(42.0));
+}
+
+#[test]
+fn test_super_is_resolved_statically() {
+    let code = r#"
+        class A {
+            method() { return "A"; }
+        }
+        class B < A {
+            method() { return "B"; }
+            test() { return super.method(); }
+        }
+        class C < B {}
+        return C().test();
+    "#;
+    run_code_str!(code, "A");
+}
+
+#[test]
+fn test_super_in_closure_and_bound_super_method() {
+    let code = r#"
+        class A {
+            say() { return "A"; }
+        }
+        class B < A {
+            getClosure() {
+                fun closure() { return super.say; }
+                return closure;
+            }
+        }
+        var say = B().getClosure()();
+        return say();
+    "#;
+    run_code_str!(code, "A");
+}
+
+#[test]
+fn test_inheriting_from_non_class_is_runtime_error() {
+    let code = r#"
+        var NotAClass = "nope";
+        class Sub < NotAClass {}
+    "#;
+    run_code_runtime_error!(code, RuntimeErrorKind::Type);
+}
+
+fn native_max(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match (&args[0], &args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a.max(*b))),
+        _ => Err(RuntimeError::new("Arguments must be numbers")),
+    }
+}
+
+#[test]
+fn test_native_function() {
+    let code = r#"
+        fun clamp_low(n) {
+            return max(n, 0);
+        }
+        return clamp_low(-5) + clamp_low(7);
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut vm = VM::init(chunk);
+    vm.define_native("max", 2, native_max);
+    assert_eq!(vm.run(), Ok(Value::Number(7.0)));
+}
+
+#[test]
+fn test_with_while() {
+    let code = r#"
+        var i = 0;
+        var sum = 0;
+        while (i < 5) {
+            sum = sum + i;
+            i = i + 1;
+        }
+        return sum;
+    "#;
+    run_code!(code, Value::Number(10.0));
+}
+
+#[test]
+fn test_arithmetic_on_string_is_runtime_error() {
+    let code = r#"
+        var a = "one";
+        return a - 1;
+    "#;
+    run_code_runtime_error!(code, RuntimeErrorKind::Type);
+}
+
+#[test]
+fn test_runtime_error_reports_line() {
+    let code = "var a = 1;\nprint a;\nprint b;";
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let error = VM::init(chunk).run().expect_err("expected a runtime error");
+    assert_eq!(error.kind, RuntimeErrorKind::UndefinedVariable);
+    assert_eq!(error.message, "Undefined variable 'b'");
+    assert_eq!(error.line, 3);
+    assert_eq!(error.to_string(), "[line 3, column 7] error: Undefined variable 'b'");
+}
+
+#[test]
+fn test_runtime_error_spans_the_failing_expression() {
+    let code = "var a = \"one\";\nvar b = a - 1;";
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let error = VM::init(chunk).run().expect_err("expected a runtime error");
+    assert_eq!(error.span, Some(Span { line: 2, column: 9, start: 23, end: 28 }));
+    assert_eq!(&code[23..28], "a - 1");
+}
+
+#[test]
+fn test_runtime_error_stack_trace() {
+    let code = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  return inner();\n}\nouter();";
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let error = VM::init(chunk).run().expect_err("expected a runtime error");
+    assert_eq!(error.line, 2);
+    assert_eq!(error.trace, vec![
+        TraceFrame { function: Some("inner".to_string()), line: 2 },
+        TraceFrame { function: Some("outer".to_string()), line: 5 },
+        TraceFrame { function: None, line: 7 },
+    ]);
+    assert_eq!(error.stack_trace(), "[line 2] in inner()\n[line 5] in outer()\n[line 7] in script\n");
+}
+
+#[test]
+fn test_native_error_stack_trace_starts_at_caller() {
+    let code = "fun f() {\n  return max(1, nil);\n}\nf();";
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut vm = VM::init(chunk);
+    vm.define_native("max", 2, native_max);
+    let error = vm.run().expect_err("expected a runtime error");
+    assert_eq!(error.kind, RuntimeErrorKind::Native);
+    assert_eq!(error.stack_trace(), "[line 2] in f()\n[line 4] in script\n");
+}
+
+#[test]
+fn test_gc_collects_instance_cycles() {
+    let code = r#"
+        class Node {}
+        var a = Node();
+        var b = Node();
+        a.next = b;
+        b.next = a;
+        a = nil;
+        b = nil;
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut vm = VM::init(chunk);
+    vm.run().expect("failed to execute vm");
+    vm.collect_garbage();
+    // Only the class and the names of the globals are still reachable
+    assert_eq!(vm.heap.object_count(), 4);
+}
+
+#[test]
+fn test_stress_gc_keeps_live_objects() {
+    let code = r#"
+        class Counter {
+            init() { this.count = 0; }
+            increment() {
+                this.count = this.count + 1;
+                return this;
+            }
+        }
+        fun makeAdder(prefix) {
+            fun add(s) { return prefix + s; }
+            return add;
+        }
+        var counter = Counter();
+        var add = makeAdder("count: ");
+        var garbage;
+        for (var i = 0; i < 10; i = i + 1) {
+            garbage = Counter();
+            counter.increment();
+        }
+        if (counter.count == 10) return add("ten");
+        return add("wrong");
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let config = GcConfig { stress: true, ..GcConfig::default() };
+    let mut vm = VM::init_with_gc(chunk, config);
+    let value = vm.run().expect("failed to execute vm");
+    assert_eq!(vm.heap.as_string(value), Some("count: ten"));
+}
+
+#[test]
+fn test_equal_strings_are_the_same_object() {
+    let code = r#"
+        var a = "hello";
+        var b = "hel" + "lo";
+        return a == b;
+    "#;
+    run_code!(code, Value::Bool(true));
+}
+
+#[test]
+fn test_more_than_256_constants() {
+    let mut code = String::new();
+    for n in 0..300 {
+        code.push_str(&format!("var v{n} = {n};\n"));
+    }
+    code.push_str("v299 = v299 + v1;\nreturn v299;");
+    run_code!(&code, Value::Number(300.0));
+}
+
+#[test]
+fn test_run_chunk_loaded_from_bytecode() {
+    let code = r#"
+        class Greeter {
+            init(name) { this.name = name; }
+            greet() { return "hi " + this.name; }
+        }
+        fun make() {
+            var greeter = Greeter("bob");
+            fun greet() { return greeter.greet(); }
+            return greet;
+        }
+        return make()();
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut bytes = Vec::new();
+    chunk.write_to(&mut bytes).expect("Failed to write bytecode");
+    let loaded = Chunk::read_from(bytes.as_slice()).expect("Failed to load bytecode");
+    assert_eq!(loaded, chunk);
+
+    let mut vm = VM::init(loaded);
+    let value = vm.run().expect("failed to execute vm");
+    assert_eq!(vm.heap.as_string(value), Some("hi bob"));
+}
+
+// Opcodes of the chunk and of every function in its pool
+fn all_opcodes(chunk: &Chunk) -> Vec<Opcode> {
+    let mut opcodes: Vec<Opcode> = disassemble(chunk).into_iter().filter_map(|instruction| instruction.opcode).collect();
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            opcodes.extend(all_opcodes(&function.chunk));
+        }
+    }
+    opcodes
+}
+
+#[test]
+fn test_functions_classes_and_properties_past_256_constants() {
+    // A lookup table fills the pool of the script and of the method before anything else
+    let table: String = (0..300).map(|n| format!("t = t + {n}.5;\n")).collect();
+    let code = format!("
+        var t = 0;
+        {table}
+        fun f() {{ return 1; }}
+        class B {{ m() {{ return 10; }} }}
+        class A < B {{
+            m() {{
+                var t = 0;
+                {table}
+                return super.m() + t;
+            }}
+        }}
+        var o = A();
+        o.x = 1000;
+        return t + f() + o.x + o.m();
+    ");
+    let chunk = compiler::compile(&code).expect("Failed to compile");
+    assert_eq!(verify(&chunk), Ok(()));
+    let opcodes = all_opcodes(&chunk);
+    for long_form in [Opcode::ClosureLong, Opcode::ClassLong, Opcode::MethodLong, Opcode::GetPropertyLong,
+                      Opcode::SetPropertyLong, Opcode::GetSuperLong] {
+        assert!(opcodes.contains(&long_form), "{long_form} is never used");
+    }
+
+    let mut listing = String::new();
+    write_chunk(&mut listing, &chunk, "script").expect("Failed to write the listing");
+    assert_eq!(assemble(&listing).as_ref(), Ok(&chunk));
+    let mut bytes = Vec::new();
+    chunk.write_to(&mut bytes).expect("Failed to write bytecode");
+    let loaded = Chunk::read_from(bytes.as_slice()).expect("Failed to load bytecode");
+    assert_eq!(loaded, chunk);
+
+    let value = VM::init(loaded).run().expect("failed to execute vm");
+    assert_eq!(value, Value::Number(45000.0 + 1.0 + 1000.0 + 45010.0));
+}
+
+#[test]
+fn test_compiled_listing_assembles_back() {
+    let code = r#"
+        var total = 0;
+        for (var i = 0; i < 10; i = i + 1) {
+            if (i == 5 or i == 7) total = total + i;
+        }
+        var name = "lox";
+        print name;
+        return total;
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut listing = String::new();
+    write_chunk(&mut listing, &chunk, "script").expect("Failed to write the listing");
+    assert_eq!(assemble(&listing), Ok(chunk));
+}
+
+#[test]
+fn test_compiled_functions_and_closures_assemble_back() {
+    let code = r#"
+        fun makeCounter() {
+            var count = 0;
+            fun increment() {
+                count = count + 1;
+                return count;
+            }
+            return increment;
+        }
+        fun add(a, b) { return a + b; }
+        var counter = makeCounter();
+        counter();
+        return add(counter(), 40);
+    "#;
+    let chunk = compiler::compile(code).expect("Failed to compile");
+    let mut listing = String::new();
+    write_chunk(&mut listing, &chunk, "script").expect("Failed to write the listing");
+    let assembled = assemble(&listing).expect("Failed to assemble");
+    assert_eq!(assembled, chunk);
+    let value = VM::init(assembled).run().expect("failed to execute vm");
+    assert_eq!(value, Value::Number(42.0));
+}
+
+#[test]
+fn test_run_assembled_closure() {
+    let chunk = assemble(include_str!("../asm/counter.loxasm")).expect("Failed to assemble");
+    assert_eq!(verify(&chunk), Ok(()));
+    let value = VM::init(chunk).run().expect("failed to execute vm");
+    assert_eq!(value, Value::Number(2.0));
+}
+
+#[test]
+fn test_multibyte_strings() {
+    let code = r#"
+        // Ünïcode in comments is skipped
+        var crab = "🦀";
+        var naïve = "héllo " + crab;
+        return naïve;
+    "#;
+    run_code_str!(code, "héllo 🦀");
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+use common::opcode::Opcode;
+use common::span::Span;
+
+/// What went wrong during execution, so embedders can react without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An operand had the wrong type, like adding a number to a boolean
+    Type,
+    UndefinedVariable,
+    UndefinedProperty,
+    /// A function was called with the wrong number of arguments
+    Arity,
+    /// Too many nested calls
+    StackOverflow,
+    /// An instruction needed more values than there are in the stack
+    StackUnderflow,
+    /// The chunk is malformed: unknown opcode, operand out of range, truncated code...
+    InvalidBytecode,
+    /// Raised by a native function
+    Native,
+}
+
+/// An error raised while executing a chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    /// Source line of the instruction that failed, 0 if unknown
+    pub line: usize,
+    /// Source code of the expression that failed, None if the chunk has no spans
+    pub span: Option<Span>,
+    /// The instruction that failed, None if it wasn't a valid opcode
+    pub opcode: Option<Opcode>,
+    /// Call frames active when the error happened, innermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+/// A function call in progress when an error happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Name of the function, None for the top-level script
+    pub function: Option<String>,
+    /// Line being executed in that function, for callers the line of the call
+    pub line: usize,
+}
+
+impl RuntimeError {
+    /// An error raised by a native function, the VM fills in where it happened
+    pub fn new(message: &str) -> RuntimeError {
+        RuntimeError::with_kind(RuntimeErrorKind::Native, message)
+    }
+
+    pub fn with_kind(kind: RuntimeErrorKind, message: &str) -> RuntimeError {
+        RuntimeError {
+            kind,
+            message: message.to_string(),
+            line: 0,
+            span: None,
+            opcode: None,
+            trace: Vec::new(),
+        }
+    }
+
+    /// The stack trace as text, one frame per line, innermost first
+    pub fn stack_trace(&self) -> String {
+        self.trace.iter().map(|frame| format!("{frame}\n")).collect()
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = self.span {
+            write!(f, "[line {}, column {}] error: {}", span.line, span.column, self.message)
+        } else if self.line > 0 {
+            write!(f, "[line {}] error: {}", self.line, self.message)
+        } else {
+            write!(f, "error: {}", self.message)
+        }
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
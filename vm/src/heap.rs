@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::object::{Class, Closure, Obj};
+use crate::value::{Value, ValueDisplay};
+
+/// Handle to an object in the heap. It stays valid as long as the object is reachable from the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+/// When the garbage collector runs
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// After a collection, the next one happens once the heap grows to this many times its live size
+    pub growth_factor: usize,
+    /// Bytes to allocate before the first collection
+    pub initial_threshold: usize,
+    /// Collect before every instruction, to catch objects that aren't properly rooted
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            growth_factor: 2,
+            initial_threshold: 1024 * 1024,
+            stress: false,
+        }
+    }
+}
+
+struct HeapEntry {
+    obj: Obj,
+    size: usize,
+    marked: bool,
+}
+
+/// Owner of every object created while running, freed by a mark-and-sweep collector
+pub struct Heap {
+    // Freed entries become None and their slot is reused by the next allocation
+    entries: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    // Every string is interned, so equal strings are the same object and compare by handle
+    strings: HashMap<Rc<str>, ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    config: GcConfig,
+}
+
+impl Heap {
+    pub fn init(config: GcConfig) -> Heap {
+        Heap {
+            entries: Vec::new(),
+            free_slots: Vec::new(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: config.initial_threshold,
+            config,
+        }
+    }
+
+    /// Moves an object to the heap. Strings must go through `intern` instead
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+        let entry = Some(HeapEntry { obj, size, marked: false });
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.entries[slot] = entry;
+                ObjRef(slot)
+            },
+            None => {
+                self.entries.push(entry);
+                ObjRef(self.entries.len() - 1)
+            },
+        }
+    }
+
+    /// The string object with the given contents, allocated on first use
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        match self.strings.get(s) {
+            Some(obj) => *obj,
+            None => self.alloc_interned(Rc::from(s)),
+        }
+    }
+
+    /// Same as `intern`, but a new object reuses the given allocation instead of copying it
+    pub fn intern_shared(&mut self, s: &Rc<str>) -> ObjRef {
+        match self.strings.get(s) {
+            Some(obj) => *obj,
+            None => self.alloc_interned(s.clone()),
+        }
+    }
+
+    fn alloc_interned(&mut self, s: Rc<str>) -> ObjRef {
+        let obj = self.alloc(Obj::String(s.clone()));
+        self.strings.insert(s, obj);
+        obj
+    }
+
+    pub fn alloc_string(&mut self, s: String) -> Value {
+        Value::Obj(self.intern(&s))
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        &self.entries[obj.0].as_ref().expect("Object used after being freed").obj
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        &mut self.entries[obj.0].as_mut().expect("Object used after being freed").obj
+    }
+
+    /// Whether enough memory was allocated since the last collection to run a new one
+    pub fn should_collect(&self) -> bool {
+        self.config.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Frees every object not reachable from the roots, returns how many were freed
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = ObjRef>) -> usize {
+        self.mark(roots);
+        let freed = self.sweep();
+        self.next_gc = (self.bytes_allocated * self.config.growth_factor).max(self.config.initial_threshold);
+        freed
+    }
+
+    fn mark(&mut self, roots: impl IntoIterator<Item = ObjRef>) {
+        let mut gray: Vec<ObjRef> = roots.into_iter().collect();
+        while let Some(obj) = gray.pop() {
+            let entry = self.entries[obj.0].as_mut().expect("Object used after being freed");
+            // Already visited, this is what stops cycles from looping forever
+            if entry.marked {
+                continue;
+            }
+            entry.marked = true;
+            entry.obj.trace(&mut gray);
+        }
+    }
+
+    fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            match entry {
+                Some(HeapEntry { marked: true, .. }) => {
+                    // Clear the mark for the next collection
+                    entry.as_mut().unwrap().marked = false;
+                },
+                Some(HeapEntry { size, obj, .. }) => {
+                    // The intern table doesn't keep strings alive
+                    if let Obj::String(s) = obj {
+                        self.strings.remove(s);
+                    }
+                    self.bytes_allocated -= *size;
+                    *entry = None;
+                    self.free_slots.push(slot);
+                    freed += 1;
+                },
+                None => {},
+            }
+        }
+        freed
+    }
+
+    /// Number of live objects
+    pub fn object_count(&self) -> usize {
+        self.entries.len() - self.free_slots.len()
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn as_string(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(obj) => match self.get(obj) {
+                Obj::String(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &Class {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            other => panic!("Expected a class, got {:?}", other),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &Closure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            other => panic!("Expected a closure, got {:?}", other),
+        }
+    }
+
+    pub fn display(&self, value: Value) -> ValueDisplay<'_> {
+        ValueDisplay { value, heap: self }
+    }
+
+    pub fn is_falsey(&self, value: Value) -> bool {
+        match value {
+            Value::Nil => true,
+            Value::Number(n) => n == 0.0,
+            Value::Bool(b) => !b,
+            Value::Obj(_) => self.as_string(value).is_some_and(str::is_empty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Instance;
+
+    fn alloc_instance(heap: &mut Heap, class: ObjRef) -> ObjRef {
+        heap.alloc(Obj::Instance(Instance::init(class)))
+    }
+
+    fn set_field(heap: &mut Heap, instance: ObjRef, name: &str, value: Value) {
+        let Obj::Instance(instance) = heap.get_mut(instance) else { panic!("Expected an instance") };
+        instance.fields.insert(name.to_string(), value);
+    }
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = Heap::init(GcConfig::default());
+        let kept = heap.intern("kept");
+        heap.intern("garbage");
+
+        assert_eq!(heap.collect([kept]), 1);
+        assert_eq!(heap.object_count(), 1);
+        assert_eq!(heap.as_string(Value::Obj(kept)), Some("kept"));
+    }
+
+    #[test]
+    fn test_collect_keeps_referenced() {
+        let mut heap = Heap::init(GcConfig::default());
+        let class = heap.alloc(Obj::Class(Class::init("Point")));
+        let instance = alloc_instance(&mut heap, class);
+        let x = heap.alloc_string("x".to_string());
+        set_field(&mut heap, instance, "x", x);
+
+        assert_eq!(heap.collect([instance]), 0);
+        assert_eq!(heap.object_count(), 3);
+    }
+
+    #[test]
+    fn test_collect_cycle() {
+        let mut heap = Heap::init(GcConfig::default());
+        let class = heap.alloc(Obj::Class(Class::init("Node")));
+        let a = alloc_instance(&mut heap, class);
+        let b = alloc_instance(&mut heap, class);
+        set_field(&mut heap, a, "next", Value::Obj(b));
+        set_field(&mut heap, b, "next", Value::Obj(a));
+
+        assert_eq!(heap.collect([class]), 2);
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn test_intern_returns_same_object() {
+        let mut heap = Heap::init(GcConfig::default());
+        let a = heap.intern("hello");
+        let shared: Rc<str> = Rc::from("hello");
+        assert_eq!(heap.intern_shared(&shared), a);
+        assert_eq!(heap.alloc_string("hel".to_string() + "lo"), Value::Obj(a));
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn test_intern_shared_reuses_allocation() {
+        let mut heap = Heap::init(GcConfig::default());
+        let shared: Rc<str> = Rc::from("name");
+        let obj = heap.intern_shared(&shared);
+        let Obj::String(s) = heap.get(obj) else { panic!("Expected a string") };
+        assert!(Rc::ptr_eq(s, &shared));
+    }
+
+    #[test]
+    fn test_collected_strings_leave_the_intern_table() {
+        let mut heap = Heap::init(GcConfig::default());
+        heap.intern("garbage");
+        heap.collect([]);
+        assert_eq!(heap.object_count(), 0);
+
+        // Interning again creates a fresh object instead of returning the freed one
+        let obj = heap.intern("garbage");
+        assert_eq!(heap.as_string(Value::Obj(obj)), Some("garbage"));
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn test_shared_strings_are_found_by_contents() {
+        let mut heap = Heap::init(GcConfig::default());
+        heap.intern_shared(&Rc::from("freed"));
+        heap.collect([]);
+
+        // The freed allocation may be reused by the next string, it must not find the old object
+        let obj = heap.intern_shared(&Rc::from("other"));
+        assert_eq!(heap.as_string(Value::Obj(obj)), Some("other"));
+        assert_eq!(heap.intern("other"), obj);
+    }
+
+    #[test]
+    fn test_freed_slots_are_reused() {
+        let mut heap = Heap::init(GcConfig::default());
+        let garbage = heap.intern("garbage");
+        let bytes = heap.bytes_allocated();
+        heap.collect([]);
+        assert_eq!(heap.bytes_allocated(), 0);
+
+        let reused = heap.intern("garbage");
+        assert_eq!(reused, garbage);
+        assert_eq!(heap.bytes_allocated(), bytes);
+    }
+
+    #[test]
+    fn test_growth_factor() {
+        let config = GcConfig { growth_factor: 2, initial_threshold: 0, stress: false };
+        let mut heap = Heap::init(config);
+        let kept = heap.intern("kept");
+        heap.collect([kept]);
+        assert!(!heap.should_collect());
+
+        // Allocating as much again doubles the heap, past the threshold
+        heap.intern("more");
+        heap.intern("else");
+        assert!(heap.should_collect());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use common::function::Function;
+
+use crate::error::RuntimeError;
+use crate::heap::ObjRef;
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Everything that lives in the heap, values point to them through an `ObjRef`
+#[derive(Debug)]
+pub enum Obj {
+    String(Rc<str>),
+    /// A function without upvalues, as stored in the constants of a chunk
+    Function(LoadedFunction),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+impl Obj {
+    /// Rough number of bytes owned by the object, used to decide when to collect
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(s) => s.len(),
+            Obj::Closure(closure) => closure.upvalues.capacity() * std::mem::size_of::<ObjRef>(),
+            Obj::Class(class) => class.name.capacity() + class.methods.capacity() * std::mem::size_of::<(String, ObjRef)>(),
+            Obj::Instance(instance) => instance.fields.capacity() * std::mem::size_of::<(String, Value)>(),
+            Obj::Native(native) => native.name.capacity(),
+            Obj::Function(_) | Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+        };
+        std::mem::size_of::<Obj>() + owned
+    }
+
+    /// Adds every object this one references to the list of objects left to visit
+    pub fn trace(&self, gray: &mut Vec<ObjRef>) {
+        match self {
+            Obj::String(_) | Obj::Native(_) | Obj::Upvalue(Upvalue::Open(_)) => {},
+            Obj::Function(function) => gray.extend(function.constants.iter().filter_map(Value::as_obj)),
+            Obj::Closure(closure) => {
+                gray.extend(&closure.upvalues);
+                gray.extend(closure.constants.iter().filter_map(Value::as_obj));
+            },
+            Obj::Upvalue(Upvalue::Closed(value)) => gray.extend(value.as_obj()),
+            Obj::Class(class) => gray.extend(class.methods.values()),
+            Obj::Instance(instance) => {
+                gray.push(instance.class);
+                gray.extend(instance.fields.values().filter_map(Value::as_obj));
+            },
+            Obj::BoundMethod(bound) => {
+                gray.extend(bound.receiver.as_obj());
+                gray.push(bound.method);
+            },
+        }
+    }
+}
+
+/// A function moved to the heap. Its constants are resolved once when it's loaded, strings
+/// to their interned object and nested functions to loaded functions, so instructions use them as is
+#[derive(Debug, Clone)]
+pub struct LoadedFunction {
+    pub function: Rc<Function>,
+    /// Same order as the constants of the function's chunk
+    pub constants: Rc<[Value]>,
+}
+
+/// A function along with the variables it captured from its enclosing functions
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub constants: Rc<[Value]>,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl Closure {
+    pub fn init(function: &LoadedFunction) -> Closure {
+        Closure {
+            function: function.function.clone(),
+            constants: function.constants.clone(),
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, ObjRef>,
+}
+
+impl Class {
+    pub fn init(name: &str) -> Class {
+        Class {
+            name: name.to_string(),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn init(class: ObjRef) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// A method accessed through an instance, it remembers the instance to use as `this`
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// A variable captured by a closure
+#[derive(Debug)]
+pub enum Upvalue {
+    /// The variable is still alive in the stack at the given slot
+    Open(usize),
+    /// The variable went out of scope, so the upvalue now owns its value
+    Closed(Value),
+}
+
+/// Signature of a Rust function callable from Lox, it gets the VM and the call arguments
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A Rust function registered as a Lox global with `VM::define_native`
+pub struct Native {
+    pub name: String,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
@@ -1,4 +1,5 @@
-use common::{Value, ValueType};
+use crate::error::{RuntimeError, RuntimeErrorKind};
+use crate::value::{Value, ValueType};
 
 pub struct Stack {
     stack: Vec<Value>,
@@ -10,6 +11,10 @@ impl std::fmt::Debug for Stack {
     }
 }
 
+fn underflow(message: &str) -> RuntimeError {
+    RuntimeError::with_kind(RuntimeErrorKind::StackUnderflow, message)
+}
+
 impl Stack {
     pub fn init() -> Stack {
         Stack {
@@ -21,53 +26,46 @@ impl Stack {
         self.stack.push(value);
     }
 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().expect("Expected stack to not be empty")
+    pub fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack.pop().ok_or_else(|| underflow("Expected stack to not be empty"))
     }
 
-    pub fn peek(&self) -> &Value {
+    pub fn peek(&self) -> Result<&Value, RuntimeError> {
         if self.is_empty() {
-            panic!("Expected stack to not be empty");
+            return Err(underflow("Expected stack to not be empty"));
         }
         self.peek_at(0)
     }
 
-    pub fn peek_at(&self, distance: usize) -> &Value {
-        if (self.len() - 1) < distance {
-            panic!("Expected stack to not be empty at distance {distance}");
+    pub fn peek_at(&self, distance: usize) -> Result<&Value, RuntimeError> {
+        if distance >= self.len() {
+            return Err(underflow(&format!("Expected stack to not be empty at distance {distance}")));
         }
-        let index = self.len() - distance - 1;
-        self.stack.get(index).expect("Expected stack to not be empty")
+        Ok(&self.stack[self.len() - distance - 1])
     }
 
-    pub fn peek_from_bottom(&self, distance: usize) -> &Value {
-        if (self.len() - 1) < distance {
-            panic!("Expected stack to not be empty at distance {distance}");
+    /// The topmost `count` values, from the deepest to the top
+    pub fn peek_top(&self, count: usize) -> Result<&[Value], RuntimeError> {
+        if count > self.len() {
+            return Err(underflow(&format!("Expected stack to have at least {count} values")));
         }
-        self.stack.get(distance).expect("Expected stack to not be empty")
+        Ok(&self.stack[self.len() - count..])
     }
 
-    pub fn is_number(&self, distance: usize) -> bool {
-        self.peek_at_is_type(distance) == ValueType::Number
+    pub fn peek_from_bottom(&self, distance: usize) -> Result<&Value, RuntimeError> {
+        self.stack.get(distance)
+            .ok_or_else(|| underflow(&format!("Expected stack to not be empty at distance {distance}")))
     }
 
-    pub fn is_string(&self, distance: usize) -> bool {
-        self.peek_at_is_type(distance) == ValueType::String
+    pub fn is_number(&self, distance: usize) -> Result<bool, RuntimeError> {
+        Ok(self.peek_at_is_type(distance)? == ValueType::Number)
     }
 
-    pub fn peek_at_is_type(&self, distance: usize) -> ValueType {
+    pub fn peek_at_is_type(&self, distance: usize) -> Result<ValueType, RuntimeError> {
         if self.is_empty() {
-            panic!("Expected stack to not be empty");
-        }
-        if (self.len() - 1) < distance {
-            panic!("Expected stack to not be empty at distance {distance}");
-        }
-        match self.peek_at(distance) {
-            Value::Nil => ValueType::Nil,
-            Value::Number(_) => ValueType::Number,
-            Value::Bool(_) => ValueType::Bool,
-            Value::String(_) => ValueType::String,
+            return Err(underflow("Expected stack to not be empty"));
         }
+        Ok(self.peek_at(distance)?.value_type())
     }
 
 
@@ -75,6 +73,11 @@ impl Stack {
         self.stack.clear();
     }
 
+    /// Drops every value above the given length
+    pub fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
+    }
+
     pub fn len(&self) -> usize {
         self.stack.len()
     }
@@ -83,14 +86,22 @@ impl Stack {
         self.stack.is_empty()
     }
 
-    pub fn set_at(&mut self, index: usize, value: Value) {
+    /// Every value from the bottom to the top
+    pub fn iter(&self) -> std::slice::Iter<'_, Value> {
+        self.stack.iter()
+    }
+
+    pub fn set_at(&mut self, index: usize, value: Value) -> Result<(), RuntimeError> {
         if self.is_empty() {
-            panic!("Expected stack to not be empty");
+            return Err(underflow("Expected stack to not be empty"));
         }
-        if (self.len() - 1) < index {
-            panic!("Expected stack to not be empty at index {index}");
+        match self.stack.get_mut(index) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            },
+            None => Err(underflow(&format!("Expected stack to not be empty at index {index}"))),
         }
-        self.stack[index] = value;
     }
 }
 
@@ -103,37 +114,31 @@ mod tests {
         let mut stack = Stack::init();
         stack.push(Value::Number(1.0));
         stack.push(Value::Number(3.0));
-        if let Value::Number(n) = stack.pop() {
-            assert_eq!(n, 3.0);
-        }
-        if let Value::Number(n) = stack.pop() {
-            assert_eq!(n, 1.0);
-        }
+        assert_eq!(stack.pop(), Ok(Value::Number(3.0)));
+        assert_eq!(stack.pop(), Ok(Value::Number(1.0)));
         assert!(stack.is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "Expected stack to not be empty")]
     fn test_stack_pop_empty() {
         let mut stack = Stack::init();
         assert!(stack.is_empty());
-        stack.pop();
+        let error = stack.pop().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::StackUnderflow);
+        assert_eq!(error.message, "Expected stack to not be empty");
     }
 
     #[test]
     fn test_stack_peek() {
         let mut stack = Stack::init();
         stack.push(Value::Number(1.0));
-        if let Value::Number(number) = stack.peek() {
-            assert_eq!(*number, 1.0);
-        }
+        assert_eq!(stack.peek(), Ok(&Value::Number(1.0)));
     }
 
     #[test]
-    #[should_panic(expected = "Expected stack to not be empty")]
     fn test_stack_peek_empty() {
         let stack = Stack::init();
-        stack.peek();
+        assert_eq!(stack.peek().unwrap_err().message, "Expected stack to not be empty");
     }
 
     #[test]
@@ -143,39 +148,36 @@ mod tests {
         stack.push(Value::Number(2.0));
         stack.push(Value::Number(3.0));
 
-        assert_eq!(*stack.peek_at(0), Value::Number(3.0));
-        assert_eq!(*stack.peek_at(1), Value::Number(2.0));
-        assert_eq!(*stack.peek_at(2), Value::Number(1.0));
+        assert_eq!(stack.peek_at(0), Ok(&Value::Number(3.0)));
+        assert_eq!(stack.peek_at(1), Ok(&Value::Number(2.0)));
+        assert_eq!(stack.peek_at(2), Ok(&Value::Number(1.0)));
     }
 
     #[test]
-    #[should_panic(expected = "Expected stack to not be empty at distance 1")]
     fn test_stack_peek_at_out_of_stack() {
         let mut stack = Stack::init();
         stack.push(Value::Number(1.0));
-        stack.peek_at(1);
+        assert_eq!(stack.peek_at(1).unwrap_err().message, "Expected stack to not be empty at distance 1");
     }
 
     #[test]
     fn test_stack_is_number() {
         let mut stack = Stack::init();
         stack.push(Value::Number(1.0));
-        assert!(stack.is_number(0));
+        assert_eq!(stack.is_number(0), Ok(true));
     }
 
     #[test]
-    #[should_panic(expected = "Expected stack to not be empty")]
     fn test_stack_is_number_empty_stack() {
         let stack = Stack::init();
-        assert!(stack.is_number(0));
+        assert_eq!(stack.is_number(0).unwrap_err().message, "Expected stack to not be empty");
     }
 
     #[test]
-    #[should_panic(expected = "Expected stack to not be empty at distance 1")]
     fn test_stack_is_number_out_of_stack() {
         let mut stack = Stack::init();
         stack.push(Value::Number(1.0));
-        assert!(stack.is_number(1));
+        assert_eq!(stack.is_number(1).unwrap_err().message, "Expected stack to not be empty at distance 1");
     }
 
     #[test]
@@ -193,12 +195,19 @@ mod tests {
         stack.push(Value::Number(2.0));
         stack.push(Value::Number(3.0));
 
-        stack.set_at(0, Value::Number(4.0));
-        stack.set_at(1, Value::Number(5.0));
-        stack.set_at(2, Value::Number(6.0));
-        assert_eq!(*stack.peek_at(0), Value::Number(6.0));
-        assert_eq!(*stack.peek_at(1), Value::Number(5.0));
-        assert_eq!(*stack.peek_at(2), Value::Number(4.0));
+        stack.set_at(0, Value::Number(4.0)).unwrap();
+        stack.set_at(1, Value::Number(5.0)).unwrap();
+        stack.set_at(2, Value::Number(6.0)).unwrap();
+        assert_eq!(stack.peek_at(0), Ok(&Value::Number(6.0)));
+        assert_eq!(stack.peek_at(1), Ok(&Value::Number(5.0)));
+        assert_eq!(stack.peek_at(2), Ok(&Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_stack_set_at_out_of_stack() {
+        let mut stack = Stack::init();
+        stack.push(Value::Number(1.0));
+        assert!(stack.set_at(1, Value::Nil).is_err());
     }
 
     #[test]
@@ -208,8 +217,8 @@ mod tests {
         stack.push(Value::Number(2.0));
         stack.push(Value::Number(3.0));
 
-        assert_eq!(*stack.peek_from_bottom(0), Value::Number(1.0));
-        assert_eq!(*stack.peek_from_bottom(1), Value::Number(2.0));
-        assert_eq!(*stack.peek_from_bottom(2), Value::Number(3.0));
+        assert_eq!(stack.peek_from_bottom(0), Ok(&Value::Number(1.0)));
+        assert_eq!(stack.peek_from_bottom(1), Ok(&Value::Number(2.0)));
+        assert_eq!(stack.peek_from_bottom(2), Ok(&Value::Number(3.0)));
     }
 }
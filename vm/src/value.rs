@@ -0,0 +1,67 @@
+use std::fmt;
+
+use crate::heap::{Heap, ObjRef};
+use crate::object::{Obj, Upvalue};
+
+#[derive(Debug,PartialEq, Eq)]
+pub enum ValueType {
+    Nil,
+    Number,
+    Bool,
+    Obj,
+}
+
+/// A Lox value. Strings, functions, classes... live in the heap and are copied around as handles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Number(f64),
+    Bool(bool),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Nil => ValueType::Nil,
+            Value::Number(_) => ValueType::Number,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Obj(_) => ValueType::Obj,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(obj) => Some(*obj),
+            _ => None,
+        }
+    }
+}
+
+/// Formats a value, looking up the objects it points to in the heap
+pub struct ValueDisplay<'a> {
+    pub value: Value,
+    pub heap: &'a Heap,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let obj = match self.value {
+            Value::Nil => return write!(f, "nil"),
+            Value::Number(n) => return write!(f, "{}", n),
+            Value::Bool(b) => return write!(f, "{}", b),
+            Value::Obj(obj) => obj,
+        };
+        match self.heap.get(obj) {
+            Obj::String(s) => write!(f, "\"{}\"", s),
+            Obj::Function(function) => write!(f, "{}", function.function),
+            Obj::Closure(closure) => write!(f, "{}", closure.function),
+            Obj::Upvalue(Upvalue::Open(_)) => write!(f, "upvalue"),
+            Obj::Upvalue(Upvalue::Closed(value)) => write!(f, "{}", self.heap.display(*value)),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => write!(f, "{} instance", self.heap.class(instance.class).name),
+            Obj::BoundMethod(bound) => write!(f, "{}", self.heap.closure(bound.method).function),
+            Obj::Native(native) => write!(f, "<native fn {}>", native.name),
+        }
+    }
+}
@@ -1,252 +1,733 @@
 use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::rc::Rc;
 
-use common::{chunk::Chunk, Constant, disassembler::disassemble_instruction, opcode::Opcode, Value};
+use common::{chunk::Chunk, Constant, function::Function, opcode::Opcode};
+use common::disassembler::{decode_instruction, write_instruction};
 
+use crate::error::{RuntimeError, RuntimeErrorKind, TraceFrame};
+use crate::heap::{GcConfig, Heap, ObjRef};
+use crate::object::{BoundMethod, Class, Closure, Instance, LoadedFunction, Native, NativeFn, Obj, Upvalue};
 use crate::stack::Stack;
+use crate::value::Value;
 
-const DEBUG: bool = true;
+const FRAMES_MAX: usize = 64;
 
-#[derive(PartialEq, Eq, Debug)]
-pub enum InterpretResult {
-    OK,
-    RuntimeError
+/// How the VM runs
+#[derive(Default)]
+pub struct VmOptions {
+    pub gc: GcConfig,
+    /// Where to print the stack and each instruction before running it, nothing is traced if None
+    pub trace_execution: Option<Box<dyn Write>>,
+}
+
+/// An ongoing function call
+struct CallFrame {
+    closure: ObjRef,
+    // The closure's function and constants, kept here to reach them without going through the heap
+    function: Rc<Function>,
+    constants: Rc<[Value]>,
+    ip: usize,
+    // Index of the first stack slot this function can use, locals are relative to it
+    slots: usize,
 }
 
 pub struct VM {
+    /// Code of the top-level script, it is moved into the first call frame when running
     pub chunk: Chunk,
     pub stack: Stack,
-    ip: usize,
-    globals: HashMap<String, Value>,
+    pub heap: Heap,
+    frames: Vec<CallFrame>,
+    // Keyed by the interned name, which is the value of the name's constant
+    globals: HashMap<ObjRef, Value>,
+    // Upvalues still pointing to a stack slot, sorted by slot
+    open_upvalues: Vec<ObjRef>,
+    // Offset of the instruction being executed in the current frame, to locate errors
+    instruction: usize,
+    trace_execution: Option<Box<dyn Write>>,
+}
+
+fn invalid_bytecode(message: &str) -> RuntimeError {
+    RuntimeError::with_kind(RuntimeErrorKind::InvalidBytecode, message)
+}
+
+fn type_error(message: &str) -> RuntimeError {
+    RuntimeError::with_kind(RuntimeErrorKind::Type, message)
 }
 
 impl VM {
     pub fn init(chunk: Chunk) -> VM {
+        VM::init_with_gc(chunk, GcConfig::default())
+    }
+
+    pub fn init_with_gc(chunk: Chunk, gc_config: GcConfig) -> VM {
+        VM::init_with_options(chunk, VmOptions { gc: gc_config, ..VmOptions::default() })
+    }
+
+    pub fn init_with_options(chunk: Chunk, options: VmOptions) -> VM {
         VM {
-            chunk: chunk,
+            chunk,
             stack: Stack::init(),
-            ip: 0,
+            heap: Heap::init(options.gc),
+            frames: Vec::new(),
             globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            instruction: 0,
+            trace_execution: options.trace_execution,
+        }
+    }
+
+    /// Registers a Rust function as a global callable from Lox code
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = Native { name: name.to_string(), arity, function };
+        let native = self.heap.alloc(Obj::Native(native));
+        let name = self.heap.intern(name);
+        self.globals.insert(name, Value::Obj(native));
+    }
+
+    /// Run a new chunk on this VM, keeping the globals defined by previous runs
+    pub fn interpret(&mut self, chunk: Chunk) -> Result<Value, RuntimeError> {
+        self.chunk = chunk;
+        self.reset();
+        self.run()
+    }
+
+    /// Runs the script until it returns. On error the VM is reset so it can be reused.
+    /// Objects in the returned value stay alive until the next run.
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        if self.frames.is_empty() {
+            // The script doesn't reserve a stack slot for itself, so its locals start at 0
+            let mut script = Function::init("");
+            script.chunk = std::mem::replace(&mut self.chunk, Chunk::init());
+            let script = self.load_function(Rc::new(script));
+            let closure = self.heap.alloc(Obj::Closure(Closure::init(&script)));
+            let LoadedFunction { function, constants } = script;
+            self.frames.push(CallFrame { closure, function, constants, ip: 0, slots: 0 });
         }
+
+        let result = self.execute();
+        if let Err(mut error) = result {
+            if !self.frames.is_empty() {
+                error.line = self.chunk().get_line(self.instruction);
+                error.span = self.chunk().get_span(self.instruction);
+                error.opcode = self.chunk().read_opcode(self.instruction);
+                error.trace = self.stack_trace();
+            }
+            self.reset();
+            return Err(error);
+        }
+        result
     }
 
-    pub fn run(&mut self) -> (InterpretResult, Option<Value>) {
+    /// Moves a function to the heap, resolving its constants and those of the functions it contains
+    fn load_function(&mut self, function: Rc<Function>) -> LoadedFunction {
+        let constants = function.chunk.constants.iter().map(|constant| match constant {
+            Constant::Number(number) => Value::Number(*number),
+            Constant::String(s) => Value::Obj(self.heap.intern_shared(s)),
+            Constant::Function(function) => {
+                let function = self.load_function(function.clone());
+                Value::Obj(self.heap.alloc(Obj::Function(function)))
+            },
+        }).collect();
+        LoadedFunction { function, constants }
+    }
+
+    /// Frees every object that can't be reached from the stack, globals, call frames or open upvalues
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self.stack.iter()
+            .chain(self.globals.values())
+            .filter_map(Value::as_obj)
+            .chain(self.globals.keys().copied())
+            .chain(self.frames.iter().map(|frame| frame.closure))
+            .chain(self.open_upvalues.iter().copied());
+        let roots: Vec<ObjRef> = roots.collect();
+        self.heap.collect(roots)
+    }
+
+    /// Where each active call frame is, innermost first
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        self.frames.iter().rev().enumerate().map(|(depth, frame)| {
+            let function = &frame.function;
+            // Callers have already moved past their Call instruction
+            let offset = if depth == 0 { self.instruction } else { frame.ip.saturating_sub(1) };
+            TraceFrame {
+                function: (!function.is_script()).then(|| function.name.clone()),
+                line: function.chunk.get_line(offset),
+            }
+        }).collect()
+    }
+
+    /// Prints the stack and the instruction about to run
+    fn trace_instruction(&mut self) {
+        let mut trace = String::from("          ");
+        for value in self.stack.iter() {
+            write!(trace, "[ {} ]", self.heap.display(*value)).expect("Writing to a String can't fail");
+        }
+        trace.push('\n');
+        // Only called while the IP is inside the code
+        if let Some(instruction) = decode_instruction(self.chunk(), self.frame().ip) {
+            write_instruction(&mut trace, self.chunk(), &instruction).expect("Writing to a String can't fail");
+        }
+
+        if let Some(out) = &mut self.trace_execution {
+            // Tracing is a debugging aid, failing to print it doesn't stop the script
+            let _ = out.write_all(trace.as_bytes());
+        }
+    }
+
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn execute(&mut self) -> Result<Value, RuntimeError> {
         loop {
-            if DEBUG {
-                println!("========= ip: {0} =============", self.ip);
-                disassemble_instruction(&self.chunk, self.ip);
-                println!("");
-                println!("{:?}", self.stack);
-                println!("===========================================");
+            // Between instructions every live object is reachable from the roots
+            if self.heap.should_collect() {
+                self.collect_garbage();
             }
-            let instruction = self.read_opcode();
+
+            self.instruction = self.frame().ip;
+            // Running off the end of the code behaves like an implicit `return nil;`
+            if self.frame().ip >= self.chunk().code_len() {
+                if let Some(value) = self.return_from_frame(Value::Nil)? {
+                    return Ok(value);
+                }
+                continue;
+            }
+            if self.trace_execution.is_some() {
+                self.trace_instruction();
+            }
+            let instruction = self.read_opcode()?;
             self.advance_ip();
             match instruction {
-                Opcode::Constant => {
-                    let constant_index = self.read_byte() as usize;
-                    let constant = self.read_constant(constant_index);
-                    let value = match constant {
-                        Constant::Number(number) => Value::Number(*number),
-                        Constant::String(s) => Value::String(s.clone()),
-                    };
+                Opcode::Constant | Opcode::ConstantLong => {
+                    let constant_index = self.read_index(instruction == Opcode::ConstantLong)?;
+                    let value = self.read_constant_value(constant_index)?;
                     self.stack.push(value);
-                    self.advance_ip();
                 }
                 Opcode::Nil => self.stack.push(Value::Nil),
                 Opcode::False => self.stack.push(Value::Bool(false)),
                 Opcode::True => self.stack.push(Value::Bool(true)),
                 Opcode::Not => {
-                    let value = self.stack.pop();
-                    self.stack.push(Value::Bool(value.is_falsey()));
+                    let value = self.stack.pop()?;
+                    self.stack.push(Value::Bool(self.heap.is_falsey(value)));
                 },
                 Opcode::Equal => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    // Strings are interned, so equal strings are the same object
                     self.stack.push(Value::Bool(a == b));
                 },
-                Opcode::Greater => self.binary_op_boolean(|a, b| a > b),
-                Opcode::Less => self.binary_op_boolean(|a, b| a < b),
+                Opcode::Greater => self.binary_op_boolean(|a, b| a > b)?,
+                Opcode::Less => self.binary_op_boolean(|a, b| a < b)?,
                 Opcode::Negate => {
-                    if !self.stack.is_number(0) {
-                        self.runtime_error("Operand must be a number");
-                        return (InterpretResult::RuntimeError, None);
-                    }
-                    if let Value::Number(constant) = self.stack.pop() {
-                        self.stack.push(Value::Number(-constant));
-                    }
+                    let Value::Number(constant) = self.stack.pop()? else {
+                        return Err(type_error("Operand must be a number"));
+                    };
+                    self.stack.push(Value::Number(-constant));
                 }
                 Opcode::Add => {
-                    if self.stack.is_string(0) && self.stack.is_string(1) {
-                        self.concatenate();
+                    let b = *self.stack.peek_at(0)?;
+                    let a = *self.stack.peek_at(1)?;
+                    if let (Some(a), Some(b)) = (self.heap.as_string(a), self.heap.as_string(b)) {
+                        let s = format!("{a}{b}");
+                        self.stack.truncate(self.stack.len() - 2);
+                        let s = self.heap.alloc_string(s);
+                        self.stack.push(s);
                     } else {
-                        self.binary_op(|a, b| a + b);
+                        self.binary_op(|a, b| a + b)?;
                     }
                 },
-                Opcode::Subtract => self.binary_op(|a, b| a - b),
-                Opcode::Multiply => self.binary_op(|a, b| a * b),
-                Opcode::Divide => self.binary_op(|a, b| a / b),
+                Opcode::Subtract => self.binary_op(|a, b| a - b)?,
+                Opcode::Multiply => self.binary_op(|a, b| a * b)?,
+                Opcode::Divide => self.binary_op(|a, b| a / b)?,
                 Opcode::Print => {
-                    let value = self.stack.pop();
-                    println!("{value}");
+                    let value = self.stack.pop()?;
+                    println!("{}", self.heap.display(value));
                 },
                 Opcode::Pop => {
-                    self.stack.pop();
-                    ()
+                    self.stack.pop()?;
                 },
                 Opcode::Push => {
-                    let value = self.read_byte();
+                    let value = self.read_byte()?;
                     self.stack.push(Value::Number(value as f64));
                     self.advance_ip();
                 },
                 Opcode::Return => {
-                    let value = self.stack.pop();
-                    return (InterpretResult::OK, Some(value));
+                    let value = self.stack.pop()?;
+                    if let Some(value) = self.return_from_frame(value)? {
+                        return Ok(value);
+                    }
                 },
-                Opcode::DefineGlobal => {
-                    let value = self.stack.pop();
-                    let name = self.read_next_constant_string();
-                    self.globals.insert(name.to_string(), value.clone());
-                    self.advance_ip();
+                Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+                    let value = self.stack.pop()?;
+                    let name = self.read_global_name(instruction == Opcode::DefineGlobalLong)?;
+                    self.globals.insert(name, value);
                 }
-                Opcode::GetGlobal => {
-                    let name = self.read_next_constant_string();
-                    if let Some(value) = self.globals.get(name) {
-                        self.stack.push(value.clone());
-                    } else {
-                        self.runtime_error("Undefined variable");
-                    }
+                Opcode::GetGlobal | Opcode::GetGlobalLong => {
+                    let name = self.read_global_name(instruction == Opcode::GetGlobalLong)?;
+                    let Some(value) = self.globals.get(&name) else {
+                        return Err(self.undefined_variable(name));
+                    };
+                    self.stack.push(*value);
                 }
-                Opcode::SetGlobal => { // TODO add tests
-                    let name = self.read_next_constant_string();
-                    if self.globals.contains_key(name) {
-                        self.globals.insert(name.to_string(), self.stack.peek().clone());
-                    } else {
-                        self.runtime_error("Undefined variable");
+                Opcode::SetGlobal | Opcode::SetGlobalLong => {
+                    let name = self.read_global_name(instruction == Opcode::SetGlobalLong)?;
+                    if !self.globals.contains_key(&name) {
+                        return Err(self.undefined_variable(name));
                     }
+                    let value = *self.stack.peek()?;
+                    self.globals.insert(name, value);
                 }
                 Opcode::GetLocal => {
                     // We have to re-push the value at the top of the stack
-                    let slot = self.read_byte() as usize;
-                    self.stack.push(self.stack.peek_from_bottom(slot).clone());
+                    let slot = self.frame().slots + self.read_byte()? as usize;
+                    self.stack.push(*self.stack.peek_from_bottom(slot)?);
                     self.advance_ip();
                 }
                 Opcode::SetLocal => {
-                    let slot = self.read_byte() as usize;
-                    self.stack.set_at(slot, self.stack.peek().clone());
+                    let slot = self.frame().slots + self.read_byte()? as usize;
+                    self.stack.set_at(slot, *self.stack.peek()?)?;
                     self.advance_ip();
                 },
+                // Jump offsets are relative to the instruction following the jump
                 Opcode::Jump => {
-                    let offset = self.read_short() as usize;
-                    self.ip += offset;
+                    let offset = self.read_short()? as usize;
+                    self.frame_mut().ip += 2 + offset;
                 },
                 Opcode::JumpIfFalse => {
-                    let offset = self.read_short() as usize;
-                    let condition = self.stack.peek();
-                    if condition.is_falsey() {
-                        self.ip += offset;
+                    let offset = self.read_short()? as usize;
+                    self.frame_mut().ip += 2;
+                    if self.heap.is_falsey(*self.stack.peek()?) {
+                        self.frame_mut().ip += offset;
+                    }
+                },
+                Opcode::Loop => {
+                    let offset = self.read_short()? as usize;
+                    let frame = self.frame_mut();
+                    frame.ip = (frame.ip + 2).checked_sub(offset)
+                        .ok_or_else(|| invalid_bytecode("Loop jumps before the start of the code"))?;
+                },
+                Opcode::Call => {
+                    let arg_count = self.read_byte()? as usize;
+                    self.advance_ip();
+                    let callee = *self.stack.peek_at(arg_count)?;
+                    self.call_value(callee, arg_count)?;
+                },
+                Opcode::Closure | Opcode::ClosureLong => {
+                    let constant_index = self.read_index(instruction == Opcode::ClosureLong)?;
+                    let function = self.read_constant_value(constant_index)?.as_obj().map(|obj| self.heap.get(obj));
+                    let Some(Obj::Function(function)) = function else {
+                        return Err(invalid_bytecode("Expected to read constant function"));
+                    };
+                    let mut closure = Closure::init(function);
+                    for _ in 0..closure.function.upvalue_count {
+                        let is_local = self.read_byte()? == 1;
+                        let index = self.read_byte_at(1)? as usize;
+                        self.frame_mut().ip += 2;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.upvalue(index)?
+                        };
+                        closure.upvalues.push(upvalue);
+                    }
+                    let closure = self.heap.alloc(Obj::Closure(closure));
+                    self.stack.push(Value::Obj(closure));
+                },
+                Opcode::GetUpvalue => {
+                    let index = self.read_byte()? as usize;
+                    self.advance_ip();
+                    let value = match self.heap.get(self.upvalue(index)?) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => *self.stack.peek_from_bottom(*slot)?,
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => return Err(invalid_bytecode("Expected an upvalue")),
+                    };
+                    self.stack.push(value);
+                },
+                Opcode::SetUpvalue => {
+                    let index = self.read_byte()? as usize;
+                    self.advance_ip();
+                    let value = *self.stack.peek()?;
+                    let upvalue = self.upvalue(index)?;
+                    match self.heap.get_mut(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack.set_at(slot, value)?;
+                        },
+                        Obj::Upvalue(Upvalue::Closed(closed)) => *closed = value,
+                        _ => return Err(invalid_bytecode("Expected an upvalue")),
+                    }
+                },
+                Opcode::CloseUpvalue => {
+                    self.stack.peek()?;
+                    self.close_upvalues(self.stack.len() - 1)?;
+                    self.stack.pop()?;
+                },
+                Opcode::Class | Opcode::ClassLong => {
+                    let name = self.read_constant_string(instruction == Opcode::ClassLong)?;
+                    let class = self.heap.alloc(Obj::Class(Class::init(&name)));
+                    self.stack.push(Value::Obj(class));
+                },
+                Opcode::GetProperty | Opcode::GetPropertyLong => {
+                    let name = self.read_constant_string(instruction == Opcode::GetPropertyLong)?;
+                    let Some(Obj::Instance(instance)) = self.stack.peek()?.as_obj().map(|obj| self.heap.get(obj)) else {
+                        return Err(type_error("Only instances have properties"));
+                    };
+
+                    // Fields shadow methods with the same name
+                    if let Some(value) = instance.fields.get(&name).copied() {
+                        self.stack.pop()?; // Instance
+                        self.stack.push(value);
                     } else {
-                        self.advance_ip();
-                        self.advance_ip();
+                        self.bind_method(instance.class, &name)?;
+                    }
+                },
+                Opcode::SetProperty | Opcode::SetPropertyLong => {
+                    let name = self.read_constant_string(instruction == Opcode::SetPropertyLong)?;
+                    let target = self.stack.peek_at(1)?.as_obj();
+                    let Some(Obj::Instance(_)) = target.map(|obj| self.heap.get(obj)) else {
+                        return Err(type_error("Only instances have fields"));
+                    };
+
+                    // The assignment is an expression, its value stays on the stack
+                    let value = self.stack.pop()?;
+                    if let Some(Obj::Instance(instance)) = target.map(|obj| self.heap.get_mut(obj)) {
+                        instance.fields.insert(name, value);
                     }
+                    self.stack.pop()?; // Instance
+                    self.stack.push(value);
+                },
+                Opcode::Method | Opcode::MethodLong => {
+                    let name = self.read_constant_string(instruction == Opcode::MethodLong)?;
+                    let method = self.stack.pop()?.as_obj()
+                        .filter(|obj| matches!(self.heap.get(*obj), Obj::Closure(_)))
+                        .ok_or_else(|| invalid_bytecode("Expected a method closure on top of the stack"))?;
+                    let Some(Obj::Class(class)) = self.stack.peek()?.as_obj().map(|obj| self.heap.get_mut(obj)) else {
+                        return Err(invalid_bytecode("Expected a class below the method"));
+                    };
+                    class.methods.insert(name, method);
+                },
+                Opcode::Inherit => {
+                    let Some(Obj::Class(superclass)) = self.stack.peek_at(1)?.as_obj().map(|obj| self.heap.get(obj)) else {
+                        return Err(type_error("Superclass must be a class"));
+                    };
+                    // Copy-down inheritance: methods declared later in the subclass override these
+                    let methods = superclass.methods.clone();
+                    let Some(Obj::Class(subclass)) = self.stack.pop()?.as_obj().map(|obj| self.heap.get_mut(obj)) else {
+                        return Err(invalid_bytecode("Expected the subclass on top of the stack"));
+                    };
+                    subclass.methods.extend(methods);
+                },
+                Opcode::GetSuper | Opcode::GetSuperLong => {
+                    let name = self.read_constant_string(instruction == Opcode::GetSuperLong)?;
+                    let superclass = self.stack.pop()?.as_obj()
+                        .filter(|obj| matches!(self.heap.get(*obj), Obj::Class(_)))
+                        .ok_or_else(|| invalid_bytecode("Expected the superclass on top of the stack"))?;
+                    self.bind_method(superclass, &name)?;
                 },
             }
         }
     }
 
-    fn read_next_constant_string(&self) -> &str {
-        let constant_index = self.read_byte() as usize;
-        if let Constant::String(str) = self.read_constant(constant_index) {
-            str
-        } else {
-            panic!("Expected to read constant string");
+    fn undefined_variable(&self, name: ObjRef) -> RuntimeError {
+        let name = self.heap.as_string(Value::Obj(name)).unwrap_or_default();
+        RuntimeError::with_kind(RuntimeErrorKind::UndefinedVariable, &format!("Undefined variable '{name}'"))
+    }
+
+    /// Replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: ObjRef, name: &str) -> Result<(), RuntimeError> {
+        let Some(method) = self.heap.class(class).methods.get(name).copied() else {
+            let message = format!("Undefined property '{name}'");
+            return Err(RuntimeError::with_kind(RuntimeErrorKind::UndefinedProperty, &message));
+        };
+        let receiver = self.stack.pop()?;
+        let bound = self.heap.alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.stack.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    /// An upvalue of the closure being executed
+    fn upvalue(&self, index: usize) -> Result<ObjRef, RuntimeError> {
+        self.heap.closure(self.frame().closure).upvalues.get(index).copied()
+            .ok_or_else(|| invalid_bytecode(&format!("Upvalue {index} out of range")))
+    }
+
+    // Stack slot an upvalue points to, None once it's closed
+    fn open_slot(&self, upvalue: ObjRef) -> Option<usize> {
+        match self.heap.get(upvalue) {
+            Obj::Upvalue(Upvalue::Open(slot)) => Some(*slot),
+            _ => None,
+        }
+    }
+
+    /// Returns the upvalue for a stack slot, reusing it if another closure already captured it
+    /// so that every closure sees the same variable
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.iter()
+            .position(|upvalue| self.open_slot(*upvalue).is_some_and(|open_slot| open_slot >= slot));
+        if let Some(position) = position {
+            let existing = self.open_upvalues[position];
+            if self.open_slot(existing) == Some(slot) {
+                return existing;
+            }
+        }
+
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        let position = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    /// Moves every open upvalue pointing at the given slot or above off the stack
+    fn close_upvalues(&mut self, last_slot: usize) -> Result<(), RuntimeError> {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue).expect("Closed upvalue in the open upvalues list");
+            if slot < last_slot {
+                break;
+            }
+            let value = *self.stack.peek_from_bottom(slot)?;
+            *self.heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Closed(value));
+            self.open_upvalues.pop();
+        }
+        Ok(())
+    }
+
+    /// Calls a value with the arguments on top of the stack
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        let not_callable = || type_error("Can only call functions and classes");
+        let callee = callee.as_obj().ok_or_else(not_callable)?;
+        // The callee and its arguments are the topmost values of the stack
+        let callee_slot = self.stack.len() - arg_count - 1;
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(callee, arg_count),
+            // A function without upvalues, it doesn't need to be wrapped by the compiler
+            Obj::Function(function) => {
+                let closure = Closure::init(function);
+                let closure = self.heap.alloc(Obj::Closure(closure));
+                self.call(closure, arg_count)
+            },
+            Obj::Class(class) => {
+                let initializer = class.methods.get("init").copied();
+                // The new instance takes the place of the class in the stack, becoming `this`
+                let instance = self.heap.alloc(Obj::Instance(Instance::init(callee)));
+                self.stack.set_at(callee_slot, Value::Obj(instance))?;
+
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(arity_error(0, arg_count)),
+                    None => Ok(()),
+                }
+            },
+            Obj::BoundMethod(bound) => {
+                let method = bound.method;
+                self.stack.set_at(callee_slot, bound.receiver)?;
+                self.call(method, arg_count)
+            },
+            Obj::Native(native) => {
+                let (name, arity, function) = (native.name.clone(), native.arity, native.function);
+                self.call_native(&name, arity, function, arg_count)
+            },
+            _ => Err(not_callable()),
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.closure(closure).function.clone();
+        let constants = self.heap.closure(closure).constants.clone();
+        if arg_count != function.arity as usize {
+            return Err(arity_error(function.arity, arg_count));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeError::with_kind(RuntimeErrorKind::StackOverflow, "Stack overflow"));
+        }
+
+        // The callee and its arguments are already on the stack, they become the first slots
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { closure, function, constants, ip: 0, slots });
+        Ok(())
+    }
+
+    // Natives run right away, without a call frame
+    fn call_native(&mut self, name: &str, arity: u8, function: NativeFn, arg_count: usize) -> Result<(), RuntimeError> {
+        if arg_count != arity as usize {
+            return Err(arity_error(arity, arg_count));
+        }
+
+        let args = self.stack.peek_top(arg_count)?.to_vec();
+        match function(self, &args) {
+            Ok(result) => {
+                // Discard the arguments and the native itself
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.stack.push(result);
+                Ok(())
+            },
+            Err(mut error) => {
+                error.message = format!("{}: {}", name, error.message);
+                Err(error)
+            },
+        }
+    }
+
+    /// Discards the current frame and pushes its return value for the caller.
+    /// Returns the value when the script itself returned and execution is over.
+    fn return_from_frame(&mut self, value: Value) -> Result<Option<Value>, RuntimeError> {
+        // The function's locals are about to be discarded, closures capturing them keep a copy
+        self.close_upvalues(self.frame().slots)?;
+        let frame = self.frames.pop().expect("Expected a frame to return from");
+        if self.frames.is_empty() {
+            return Ok(Some(value));
+        }
+        self.stack.truncate(frame.slots);
+        self.stack.push(value);
+        Ok(None)
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Expected a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Expected a call frame")
+    }
+
+    /// Chunk of the function currently being executed
+    fn chunk(&self) -> &Chunk {
+        &self.frame().function.chunk
+    }
+
+    /// The constant string whose index is at current IP, moving past the index
+    fn read_constant_string(&mut self, long: bool) -> Result<String, RuntimeError> {
+        let constant_index = self.read_index(long)?;
+        match self.read_constant(constant_index)? {
+            Constant::String(str) => Ok(str.to_string()),
+            _ => Err(invalid_bytecode("Expected to read constant string")),
         }
     }
 
+    /// The interned string object for the global name whose index is at current IP
+    fn read_global_name(&mut self, long: bool) -> Result<ObjRef, RuntimeError> {
+        let constant_index = self.read_index(long)?;
+        match self.read_constant_value(constant_index)? {
+            Value::Obj(name) if matches!(self.heap.get(name), Obj::String(_)) => Ok(name),
+            _ => Err(invalid_bytecode("Expected to read constant string")),
+        }
+    }
+
+    /// A constant of the current function, as resolved when the function was loaded
+    fn read_constant_value(&self, index: usize) -> Result<Value, RuntimeError> {
+        self.frame().constants.get(index).copied()
+            .ok_or_else(|| invalid_bytecode(&format!("Constant {index} out of range")))
+    }
+
     fn advance_ip(&mut self) {
-        let new_position = (self.ip as i32 + 1) as usize;
-        self.ip = new_position.min(self.chunk.code_len() - 1).max(0);
+        self.frame_mut().ip += 1;
     }
 
     /// Reads a raw byte from the chunk's code at current IP
-    fn read_byte(&self) -> u8 {
-        self.chunk.read_byte(self.ip)
+    fn read_byte(&self) -> Result<u8, RuntimeError> {
+        self.read_byte_at(0)
+    }
+
+    /// Reads a raw byte at a distance from the current IP
+    fn read_byte_at(&self, distance: usize) -> Result<u8, RuntimeError> {
+        self.chunk().read_byte(self.frame().ip + distance)
+            .ok_or_else(|| invalid_bytecode("Unexpected end of code"))
     }
 
     /// Reads a short (2 bytes) from the chunk's code at current IP
-    fn read_short(&self) -> u16 {
-        let byte1 = self.chunk.read_byte(self.ip) as u16;
-        let byte2 = self.chunk.read_byte(self.ip + 1) as u16;
-        (byte1 << 8) | byte2
+    fn read_short(&self) -> Result<u16, RuntimeError> {
+        self.read_short_at(0)
+    }
+
+    fn read_short_at(&self, distance: usize) -> Result<u16, RuntimeError> {
+        let byte1 = self.read_byte_at(distance)? as u16;
+        let byte2 = self.read_byte_at(distance + 1)? as u16;
+        Ok((byte1 << 8) | byte2)
+    }
+
+    /// Reads an index operand, 1 byte or 3 for the long forms, and moves past it
+    fn read_index(&mut self, long: bool) -> Result<usize, RuntimeError> {
+        let index = if long {
+            (self.read_byte_at(0)? as usize) << 16 | (self.read_short_at(1)? as usize)
+        } else {
+            self.read_byte()? as usize
+        };
+        self.frame_mut().ip += if long { 3 } else { 1 };
+        Ok(index)
     }
 
     /// Reads an opcode from the chunk's code at current IP
-    fn read_opcode(&mut self) -> Opcode {
-        self.chunk.read_opcode(self.ip)
+    fn read_opcode(&mut self) -> Result<Opcode, RuntimeError> {
+        let byte = self.read_byte()?;
+        Opcode::from_byte(byte).ok_or_else(|| invalid_bytecode(&format!("Unknown opcode {byte}")))
     }
 
     /// Read a constant from the chunk's constant pool given it's index
-    fn read_constant(&self, index: usize) -> &Constant {
-        &self.chunk.read_constant(index)
-    }
-
-    fn concatenate(&mut self) {
-        if self.stack.is_string(0) && self.stack.is_string(1) {
-            if let (Value::String(s2), Value::String(s1)) = (self.stack.pop(), self.stack.pop()) {
-                let mut s = s1.clone();
-                s.push_str(&s2);
-                self.stack.push(Value::String(s));
-            } else {
-                panic!("Expected to concatenate strings");
-            }
-        }
+    fn read_constant(&self, index: usize) -> Result<&Constant, RuntimeError> {
+        self.chunk().read_constant(index)
+            .ok_or_else(|| invalid_bytecode(&format!("Constant {index} out of range")))
     }
 
-    fn binary_op<F>(&mut self, op: F) where F: Fn(f64, f64) -> f64 {
-        if let (Value::Number(b), Value::Number(a)) = (self.stack.pop(), self.stack.pop()) {
-            self.stack.push(Value::Number(op(a, b)));
-        } else {
-            panic!("Operands must be numbers");
-        }
+    fn binary_op<F>(&mut self, op: F) -> Result<(), RuntimeError> where F: Fn(f64, f64) -> f64 {
+        let (b, a) = self.pop_number_operands()?;
+        self.stack.push(Value::Number(op(a, b)));
+        Ok(())
     }
 
-    fn binary_op_boolean<F>(&mut self, op: F) where F: Fn(f64, f64) -> bool {
-        if let (Value::Number(b), Value::Number(a)) = (self.stack.pop(), self.stack.pop()) {
-            self.stack.push(Value::Bool(op(a, b)));
-        } else {
-            panic!("Operands must be numbers");
-        }
+    fn binary_op_boolean<F>(&mut self, op: F) -> Result<(), RuntimeError> where F: Fn(f64, f64) -> bool {
+        let (b, a) = self.pop_number_operands()?;
+        self.stack.push(Value::Bool(op(a, b)));
+        Ok(())
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        let instruction = self.ip - 1;
-        let line = self.chunk.get_line(instruction);
-        eprintln!("[line {line}] error: {message}");
-        self.stack.clear();
+    /// Pops the two operands of a binary operation, right side first
+    fn pop_number_operands(&mut self) -> Result<(f64, f64), RuntimeError> {
+        match (self.stack.pop()?, self.stack.pop()?) {
+            (Value::Number(b), Value::Number(a)) => Ok((b, a)),
+            _ => Err(type_error("Operands must be numbers")),
+        }
     }
 }
 
+fn arity_error(arity: u8, arg_count: usize) -> RuntimeError {
+    let message = format!("Expected {} arguments but got {}", arity, arg_count);
+    RuntimeError::with_kind(RuntimeErrorKind::Arity, &message)
+}
+
 #[cfg(test)]
 mod tests {
-    use common::*;
     use common::{run_and_expect, run_and_expect_str, write_constant, write_return, write_string};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use common::assembler::assemble;
     use common::chunk::Chunk;
     use common::Constant;
+    use common::function::Function;
     use common::opcode::Opcode;
 
-    use crate::vm::VM;
+    use crate::error::{RuntimeError, RuntimeErrorKind};
+    use crate::object::Obj;
+    use crate::value::Value;
+    use crate::vm::{VmOptions, VM};
 
     #[test]
     fn test_return_float() {
         let mut vm = VM::init(Chunk::init());
-        write_constant!(vm, 3.14);
+        write_constant!(vm, 2.75);
         write_return!(vm);
-        run_and_expect!(vm, Value::Number(3.14));
+        run_and_expect!(vm, Value::Number(2.75));
     }
 
     #[test]
     fn test_float_equality() {
         let mut vm = VM::init(Chunk::init());
-        write_constant!(vm, 3.14);
-        write_constant!(vm, 3.14);
+        write_constant!(vm, 2.75);
+        write_constant!(vm, 2.75);
         vm.chunk.write_opcode(Opcode::Equal, 124);
         write_return!(vm);
         run_and_expect!(vm, Value::Bool(true));
@@ -312,7 +793,7 @@ mod tests {
     #[test]
     fn test_global_variables() {
         let mut vm = VM::init(Chunk::init());
-        vm.chunk.add_constant(Constant::String("myvar".to_string()));
+        vm.chunk.add_constant(Constant::String("myvar".into()));
         vm.chunk.add_constant(Constant::Number(4.0));
         vm.chunk.write_opcode(Opcode::Constant, 123);
         vm.chunk.write_byte(1, 123);
@@ -367,7 +848,7 @@ mod tests {
     fn test_jump() {
         let mut vm = VM::init(Chunk::init());
         vm.chunk.write_opcode(Opcode::Jump, 123);
-        vm.chunk.write_short(4, 123); // Skip 4 instructions towards the Multiply
+        vm.chunk.write_short(2, 123); // Skip the Add and Return towards the Multiply
         vm.stack.push(Value::Number(2.0));
         vm.stack.push(Value::Number(3.0));
         vm.chunk.write_opcode(Opcode::Add, 124);
@@ -384,7 +865,7 @@ mod tests {
         let mut vm = VM::init(Chunk::init());
         vm.stack.push(Value::Bool(false));
         vm.chunk.write_opcode(Opcode::JumpIfFalse, 123);
-        vm.chunk.write_short(6, 123); // Skip 4 instructions towards the Push 6
+        vm.chunk.write_short(4, 123); // Skip 4 bytes towards the Push 6
         vm.chunk.write_opcode(Opcode::Pop, 124);
 
         vm.chunk.write_opcode(Opcode::Push, 124);
@@ -407,7 +888,7 @@ mod tests {
         let mut vm = VM::init(Chunk::init());
         vm.stack.push(Value::Bool(true));
         vm.chunk.write_opcode(Opcode::JumpIfFalse, 123);
-        vm.chunk.write_short(6, 123); // Skip 4 instructions towards the Push 6
+        vm.chunk.write_short(4, 123); // Skip 4 bytes towards the Push 6
         vm.chunk.write_opcode(Opcode::Pop, 124);
 
         vm.chunk.write_opcode(Opcode::Push, 124);
@@ -420,4 +901,225 @@ mod tests {
 
         run_and_expect!(vm, Value::Number(5.0));
     }
+
+    #[test]
+    fn test_call() {
+        let chunk = assemble("
+            .function double 1 0
+                GET_LOCAL 1
+                PUSH 2
+                MULTIPLY
+                RETURN
+            .end
+            CONSTANT 0
+            PUSH 21
+            CALL 1
+            RETURN
+        ").unwrap();
+        let mut vm = VM::init(chunk);
+        run_and_expect!(vm, Value::Number(42.0));
+    }
+
+    #[test]
+    fn test_call_wrong_arity() {
+        let mut vm = VM::init(Chunk::init());
+        vm.chunk.write_constant(Constant::Function(Rc::new(Function::init("f"))), 123);
+        vm.chunk.write_opcode(Opcode::Nil, 123);
+        vm.chunk.write_opcode(Opcode::Call, 123);
+        vm.chunk.write_byte(1, 123);
+        write_return!(vm);
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::Arity);
+        assert_eq!(error.message, "Expected 0 arguments but got 1");
+        assert_eq!(error.opcode, Some(Opcode::Call));
+    }
+
+    fn native_add(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+        match (&args[0], &args[1]) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            _ => Err(RuntimeError::new("Arguments must be numbers")),
+        }
+    }
+
+    // Writes `add(a, b)` calling the native defined above
+    fn write_native_add_call(vm: &mut VM, a: Constant, b: Constant) {
+        let name = vm.chunk.add_constant(Constant::String("add".into()));
+        vm.chunk.write_opcode(Opcode::GetGlobal, 123);
+        vm.chunk.write_byte(name as u8, 123);
+        vm.chunk.write_constant(a, 123);
+        vm.chunk.write_constant(b, 123);
+        vm.chunk.write_opcode(Opcode::Call, 123);
+        vm.chunk.write_byte(2, 123);
+        write_return!(vm);
+    }
+
+    #[test]
+    fn test_call_native() {
+        let mut vm = VM::init(Chunk::init());
+        vm.define_native("add", 2, native_add);
+        write_native_add_call(&mut vm, Constant::Number(40.0), Constant::Number(2.0));
+        run_and_expect!(vm, Value::Number(42.0));
+    }
+
+    #[test]
+    fn test_call_native_error() {
+        let mut vm = VM::init(Chunk::init());
+        vm.define_native("add", 2, native_add);
+        write_native_add_call(&mut vm, Constant::Number(40.0), Constant::String("two".into()));
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::Native);
+        assert_eq!(error.message, "add: Arguments must be numbers");
+    }
+
+    #[test]
+    fn test_call_native_wrong_arity() {
+        let mut vm = VM::init(Chunk::init());
+        vm.define_native("add", 3, native_add);
+        write_native_add_call(&mut vm, Constant::Number(40.0), Constant::Number(2.0));
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::Arity);
+    }
+
+    #[test]
+    fn test_loop() {
+        let chunk = assemble("
+                JUMP -> push
+            done:
+                RETURN
+            push:
+                PUSH 7
+                LOOP -> done
+        ").unwrap();
+        let mut vm = VM::init(chunk);
+        run_and_expect!(vm, Value::Number(7.0));
+    }
+
+    #[test]
+    fn test_binary_op_type_error() {
+        let mut vm = VM::init(Chunk::init());
+        write_constant!(vm, 1.0);
+        write_string!(vm, "one");
+        vm.chunk.write_opcode(Opcode::Subtract, 124);
+        write_return!(vm);
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::Type);
+        assert_eq!(error.message, "Operands must be numbers");
+        assert_eq!(error.line, 124);
+        assert_eq!(error.opcode, Some(Opcode::Subtract));
+    }
+
+    #[test]
+    fn test_pop_empty_stack_is_error() {
+        let mut vm = VM::init(Chunk::init());
+        vm.chunk.write_opcode(Opcode::Pop, 123);
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::StackUnderflow);
+        assert_eq!(error.opcode, Some(Opcode::Pop));
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_error() {
+        let mut vm = VM::init(Chunk::init());
+        vm.chunk.write_byte(255, 123);
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::InvalidBytecode);
+        assert_eq!(error.message, "Unknown opcode 255");
+        assert_eq!(error.opcode, None);
+    }
+
+    #[test]
+    fn test_undefined_global_is_error() {
+        let mut vm = VM::init(Chunk::init());
+        let name = vm.chunk.add_constant(Constant::String("missing".into()));
+        vm.chunk.write_opcode(Opcode::GetGlobal, 123);
+        vm.chunk.write_byte(name as u8, 123);
+        write_return!(vm);
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::UndefinedVariable);
+        assert_eq!(error.message, "Undefined variable 'missing'");
+        assert_eq!(error.line, 123);
+        assert_eq!(error.opcode, Some(Opcode::GetGlobal));
+    }
+
+    #[test]
+    fn test_constant_long() {
+        let mut vm = VM::init(Chunk::init());
+        for n in 0..300 {
+            vm.chunk.add_constant(Constant::Number(n as f64));
+        }
+        vm.chunk.write_constant(Constant::Number(1000.0), 123);
+        write_return!(vm);
+        assert_eq!(vm.chunk.code[0], Opcode::ConstantLong as u8);
+
+        run_and_expect!(vm, Value::Number(1000.0));
+    }
+
+    #[test]
+    fn test_global_long() {
+        let mut vm = VM::init(Chunk::init());
+        for n in 0..300 {
+            vm.chunk.add_constant(Constant::Number(n as f64));
+        }
+        let name = vm.chunk.add_constant(Constant::String("big".into()));
+        write_constant!(vm, 4.0);
+        vm.chunk.write_indexed(Opcode::DefineGlobal, name, 123);
+        write_constant!(vm, 2.0);
+        vm.chunk.write_indexed(Opcode::SetGlobal, name, 123);
+        vm.chunk.write_opcode(Opcode::Pop, 123);
+        vm.chunk.write_indexed(Opcode::GetGlobal, name, 123);
+        write_return!(vm);
+
+        run_and_expect!(vm, Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_constants_are_resolved_when_loaded() {
+        let mut inner = Function::init("inner");
+        inner.chunk.add_constant(Constant::String("name".into()));
+        let mut script = Function::init("");
+        script.chunk.add_constant(Constant::Number(1.0));
+        script.chunk.add_constant(Constant::Function(Rc::new(inner)));
+
+        let mut vm = VM::init(Chunk::init());
+        let loaded = vm.load_function(Rc::new(script));
+        assert_eq!(loaded.constants[0], Value::Number(1.0));
+        let Some(Obj::Function(inner)) = loaded.constants[1].as_obj().map(|obj| vm.heap.get(obj)) else { panic!() };
+        // Nested functions are loaded too, their strings are the interned objects
+        let name = inner.constants[0];
+        assert_eq!(name, Value::Obj(vm.heap.intern("name")));
+    }
+
+    // Trace output shared with the test, the VM owns its writer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_execution() {
+        let buffer = SharedBuffer::default();
+        let options = VmOptions { trace_execution: Some(Box::new(buffer.clone())), ..VmOptions::default() };
+        let mut vm = VM::init_with_options(Chunk::init(), options);
+        write_constant!(vm, 1.0);
+        write_return!(vm);
+        run_and_expect!(vm, Value::Number(1.0));
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(trace, "          \n0000  123 CONSTANT            0 1\n          [ 1 ]\n0002  124 RETURN\n");
+    }
 }